@@ -1,18 +1,32 @@
+use std::collections::HashMap;
 use std::io::Write;
 
+use customasm::util::FileServer;
+
 const OPCODES: &str = include_str!("opcodes.txt");
 
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=opcodes.txt");
+    println!("cargo:rerun-if-changed=src/asm");
+
     // Code generation
     let output = std::env::var("OUT_DIR").unwrap();
     let mut opcodes = std::fs::File::create(format!("{output}/opcodes.rs")).unwrap();
     let mut parsing = std::fs::File::create(format!("{output}/parsing.rs")).unwrap();
+    let mut metadata = std::fs::File::create(format!("{output}/metadata.rs")).unwrap();
 
-    opcodes.write_all(b"#[derive(Debug, Clone, Copy)]pub enum Opcode{").unwrap();
+    opcodes.write_all(b"#[derive(PartialEq, Eq, Debug, Clone, Copy)]pub enum Opcode{").unwrap();
 
-    parsing.write_all(b"impl<B:Bus,C>Cpu<B,C>{\n///Fetches the next instruction and its operands.\npub fn fetch(&mut self)->Instruction{let opcode=self.load_pc();match opcode{").unwrap();
+    parsing.write_all(b"impl<B:Bus,C>Cpu<B,C>{\n///Fetches the next instruction and its operands.\npub fn fetch(&mut self)->Result<Instruction,Error>{let opcode=self.load_pc();Ok(match opcode{").unwrap();
 
     let mut names = Vec::<&str>::new();
+    // One metadata table for the NMOS cores and one for the 65C02, indexed by opcode.
+    let mut nmos = vec![String::from("None"); 256];
+    let mut cmos = vec![String::from("None"); 256];
+    // The same table without the operands and without a cpu, used by the cycle stepped core which fetches them one
+    // cycle at a time and by the disassembler.
+    let mut decode = String::from("impl Instruction{\n///Decodes an opcode, the operands are left as 0.\npub(crate) fn decode_opcode(variant:Variant,opcode:u8)->Option<Instruction>{Some(match opcode{");
 
     for i in OPCODES.lines() {
         let line: Vec<&str> = i.split_whitespace().collect();
@@ -20,12 +34,24 @@ fn main() {
         let opcode = line[0];
         let name = line[1];
         let mode = line[2];
+        // Opcodes that only exist on some variants are tagged in the 7th column.
+        let tag = line.get(6).copied();
+        let guard = match tag {
+            None => "",
+            Some("undocumented") => " if self.variant.has_undocumented()",
+            Some("cmos") => " if self.variant.is_cmos()",
+            Some(tag) => {
+                println!("{tag}");
+                unreachable!()
+            }
+        };
         let operands = match mode {
             "Implied" | "Accumulator" => "",
-            "Zero" | "ZeroX" | "ZeroY" | "Relative" | "IndirectX" | "IndirectY" | "Immediate" => {
+            "Zero" | "ZeroX" | "ZeroY" | "Relative" | "IndirectX" | "IndirectY" | "ZeroIndirect" | "Immediate" => {
                 "(self.load_pc())"
             }
-            "Indirect" | "Absolute" | "AbsoluteX" | "AbsoluteY" => "(self.load_pc_u16())",
+            "Indirect" | "AbsoluteIndirectX" | "Absolute" | "AbsoluteX" | "AbsoluteY" => "(self.load_pc_u16())",
+            "ZeroRelative" => "(self.load_pc(),self.load_pc())",
             _ => {
                 println!("{mode}");
                 unreachable!()
@@ -35,7 +61,56 @@ fn main() {
             names.push(name);
         }
 
-        parsing.write_all(format!("{opcode}=>Instruction{{opcode:Opcode::{name},addr:Address::{mode}{operands} }},").as_bytes()).unwrap();
+        // The length follows from the addressing mode, so opcodes.txt has no column for it that could disagree.
+        let bytes = match mode {
+            "Implied" | "Accumulator" => 1,
+            "Zero" | "ZeroX" | "ZeroY" | "Relative" | "IndirectX" | "IndirectY" | "ZeroIndirect" | "Immediate" => 2,
+            _ => 3,
+        };
+        let code = usize::from_str_radix(opcode.trim_start_matches("0x"), 16).unwrap();
+        for (table, is_cmos) in [(&mut nmos, false), (&mut cmos, true)] {
+            if tag == Some(if is_cmos { "undocumented" } else { "cmos" }) {
+                continue;
+            }
+            // The cycles, the penalties and the flags can differ between the NMOS core and the 65C02, written as nmos/cmos.
+            let column = |i: usize| {
+                let mut values = line[i].split('/');
+                let nmos = values.next().unwrap();
+                values.next().filter(|_| is_cmos).unwrap_or(nmos)
+            };
+            let cycles = column(3);
+            let penalty = column(4);
+            let flags = column(5).chars().fold(0u8, |mask, flag| {
+                mask | match flag {
+                    'N' => 0x80,
+                    'V' => 0x40,
+                    'D' => 0x08,
+                    'I' => 0x04,
+                    'Z' => 0x02,
+                    'C' => 0x01,
+                    '-' => 0,
+                    _ => {
+                        println!("{flag}");
+                        unreachable!()
+                    }
+                }
+            });
+            table[code] = format!(
+                "Some(Metadata{{opcode:Opcode::{name},bytes:{bytes},cycles:{cycles},penalty:Penalty{{page:{},branch:{},decimal:{}}},flags:{flags}}})",
+                penalty.contains('p'),
+                penalty.contains('b'),
+                penalty.contains('d'),
+            );
+        }
+
+        parsing.write_all(format!("{opcode}{guard}=>Instruction{{opcode:Opcode::{name},code:{opcode},addr:Address::{mode}{operands} }},").as_bytes()).unwrap();
+        let operands = match mode {
+            "Implied" | "Accumulator" => "",
+            "ZeroRelative" => "(0,0)",
+            _ => "(0)",
+        };
+        let guard = guard.replace("self.variant", "variant");
+        decode.push_str(&format!("{opcode}{guard}=>Instruction{{opcode:Opcode::{name},code:{opcode},addr:Address::{mode}{operands} }},"));
     }
 
     for name in names {
@@ -44,8 +119,16 @@ fn main() {
 
     opcodes.write_all(b"}").unwrap();
     parsing
-        .write_all(b"_=>panic!(\"\\\"illegal\\\" opcode\")}}}")
+        .write_all(b"_=>return Err(Error::IllegalOpcode{pc:self.pc.wrapping_sub(1),opcode})})}}")
         .unwrap();
+    decode.push_str("_=>return None})}}");
+    opcodes.write_all(decode.as_bytes()).unwrap();
+
+    for (name, table) in [("NMOS", nmos), ("CMOS", cmos)] {
+        metadata
+            .write_all(format!("static {name}:[Option<Metadata>;256]=[{}];", table.join(",")).as_bytes())
+            .unwrap();
+    }
 
     // format the output
     std::process::Command::new("rustfmt")
@@ -60,6 +143,12 @@ fn main() {
         .unwrap()
         .wait()
         .unwrap();
+    std::process::Command::new("rustfmt")
+        .arg(format!("{output}/metadata.rs"))
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap();
 
     // Assemble the assembly source using customasm
     let mut fileserver = customasm::util::FileServerReal::new();
@@ -73,4 +162,128 @@ fn main() {
     ];
 
     customasm::driver::drive(&args, &mut fileserver).unwrap();
+
+    // And the test program for the built-in assembler, the tests check it gives the same bytes
+    let mut fileserver = customasm::util::FileServerMock::new();
+    fileserver.add("6502.asm", include_str!("src/asm/6502.asm"));
+    fileserver.add("test.asm", format!("#include \"6502.asm\"\n{}", include_str!("src/asm/assembler_test.asm")));
+    let args = vec![
+        String::new(),
+        String::from("test.asm"),
+        String::from("-f"),
+        String::from("binary"),
+        String::from("-o"),
+        String::from("test.bin"),
+    ];
+    customasm::driver::drive(&args, &mut fileserver).unwrap();
+    let program = fileserver.get_bytes(customasm::diagn::RcReport::new(), "test.bin", None).unwrap();
+    std::fs::write(format!("{output}/assembler_test"), program).unwrap();
+
+    // Assemble every rule of the ruledef, the tests check `fetch` decodes each one to what the rule says
+    let ruledef = include_str!("src/asm/6502.asm");
+    // (source, mnemonic, addressing mode, opcode), without a mode for the shapes the generator doesn't know
+    let mut entries = Vec::new();
+    let ruledef_rules = ruledef[ruledef.find("#ruledef").unwrap()..].lines().filter_map(|line| line.split_once("=>"));
+    for (pattern, encoding) in ruledef_rules {
+        let (mnemonic, mut rest) = pattern.trim().split_once(char::is_whitespace).unwrap_or((pattern.trim(), ""));
+        // the first byte the rule emits
+        let opcode = encoding
+            .split(|c: char| c.is_whitespace() || c == '@')
+            .find(|token| !token.is_empty())
+            .and_then(|token| u8::from_str_radix(token.strip_prefix("0x")?, 16).ok());
+        // The operand with made up values for the parameters, and its shape to work out the addressing mode.
+        let mut operand = String::new();
+        let mut shape = String::new();
+        while let Some(start) = rest.find('{') {
+            let end = rest.find('}').unwrap();
+            let (value, kind) = match rest[start + 1..end].split(':').nth(1).map_or("", str::trim) {
+                "i8" | "u8" => ("0x12", "zp"),
+                "u16" => ("0x1234", "abs"),
+                "cpu6502_reladdr" => ("$ + 0x14", "rel"),
+                // left for the tests to report
+                _ => ("0", "?"),
+            };
+            operand.push_str(&format!("{}{value}", &rest[..start]));
+            shape.push_str(&format!("{}{kind}", &rest[..start]));
+            rest = &rest[end + 1..];
+        }
+        let operand = format!("{operand}{rest}").split_whitespace().collect::<Vec<_>>().join(" ");
+        let shape: String = format!("{shape}{rest}").chars().filter(|c| !c.is_whitespace()).collect();
+        let mode = match shape.trim_start_matches('<') {
+            "" => "Implied",
+            "a" => "Accumulator",
+            "#zp" => "Immediate(0x12)",
+            "zp" => "Zero(0x12)",
+            "zp,x" => "ZeroX(0x12)",
+            "zp,y" => "ZeroY(0x12)",
+            "abs" => "Absolute(0x1234)",
+            "abs,x" => "AbsoluteX(0x1234)",
+            "abs,y" => "AbsoluteY(0x1234)",
+            "(zp,x)" => "IndirectX(0x12)",
+            "(zp),y" => "IndirectY(0x12)",
+            "(abs)" => "Indirect(0x1234)",
+            // the target is 0x14 bytes on from the start of the branch, 0x12 from its end
+            "rel" => "Relative(0x12)",
+            // an operand the generator doesn't know, left without bytes or a mode for the tests to report
+            _ => {
+                entries.push((pattern.trim().to_string(), mnemonic, None, opcode));
+                continue;
+            }
+        };
+        entries.push((format!("{mnemonic} {operand}").trim().to_string(), mnemonic, Some(mode), opcode));
+    }
+
+    // One customasm run for all of them, each after a label to split the output up again. If that fails they're
+    // assembled one at a time, and the ones customasm can't assemble are left without bytes for the tests to report.
+    let sources: Vec<&str> = entries.iter().filter(|entry| entry.2.is_some()).map(|entry| entry.0.as_str()).collect();
+    let source: String = sources.iter().enumerate().map(|(i, source)| format!("rule_{i}:\n{source}\n")).collect();
+    let assembled: Vec<Vec<u8>> = match assemble(ruledef, &format!("{source}rule_{}:\n", sources.len())) {
+        Some((bytes, symbols)) => {
+            let offset = |i: usize| symbols[&format!("rule_{i}")];
+            (0..sources.len()).map(|i| bytes[offset(i)..offset(i + 1)].to_vec()).collect()
+        }
+        None => sources.iter().map(|source| assemble(ruledef, source).map_or(Vec::new(), |(bytes, _)| bytes)).collect(),
+    };
+    let mut assembled = assembled.into_iter();
+    let mut rules = String::from("&[");
+    for (source, mnemonic, mode, opcode) in entries {
+        match mode {
+            Some(mode) => {
+                let bytes = assembled.next().unwrap();
+                rules.push_str(&format!("({source:?},&{bytes:?},{mnemonic:?},Some(Address::{mode}),{opcode:?}),"));
+            }
+            None => rules.push_str(&format!("({source:?},&[],{mnemonic:?},None,{opcode:?}),")),
+        }
+    }
+    rules.push(']');
+    std::fs::write(format!("{output}/ruledef.rs"), rules).unwrap();
+}
+
+/// Assembles `source` at 0x0200 with the ruledef, returns the bytes and the offsets of the labels from there, or None
+/// if customasm can't assemble it.
+fn assemble(ruledef: &str, source: &str) -> Option<(Vec<u8>, HashMap<String, usize>)> {
+    let mut fileserver = customasm::util::FileServerMock::new();
+    fileserver.add("6502.asm", ruledef);
+    fileserver.add("rules.asm", format!("#include \"6502.asm\"\n#addr 0x0200\n{source}\n"));
+    let args = vec![
+        String::new(),
+        String::from("rules.asm"),
+        String::from("-f"),
+        String::from("binary"),
+        String::from("-o"),
+        String::from("rules.bin"),
+        String::from("--symbol=rules.sym"),
+    ];
+    customasm::driver::drive(&args, &mut fileserver).ok()?;
+    let report = customasm::diagn::RcReport::new();
+    let bytes = fileserver.get_bytes(report.clone(), "rules.bin", None).unwrap().split_off(0x0200);
+    let symbols = String::from_utf8(fileserver.get_bytes(report, "rules.sym", None).unwrap()).unwrap();
+    let symbols = symbols
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(" = 0x")?;
+            Some((name.to_string(), usize::from_str_radix(value, 16).ok()? - 0x0200))
+        })
+        .collect();
+    Some((bytes, symbols))
 }
@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::{Address, Bus, Disassembler, Instruction, Metadata, Opcode, Variant};
+
+/// Where the code goes after an instruction.
+enum Flow {
+    Next,
+    /// A conditional branch, to the target or the next instruction.
+    Branch(u16),
+    /// A jump, None if the target is only known at run time.
+    Jump(Option<u16>),
+    /// A subroutine call, which comes back to the next instruction.
+    Call(u16),
+    /// Returns, interrupts or locks up.
+    Stop,
+}
+
+/// A run of instructions that's only entered at the top and only left at the bottom.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Block {
+    pub start: u16,
+    /// The addresses of the instructions.
+    pub instructions: Vec<u16>,
+    /// The blocks control can go to next.
+    pub successors: Vec<u16>,
+    /// The subroutines called from the block.
+    pub calls: Vec<u16>,
+    /// Whether the block ends with a jump through a pointer, which isn't followed.
+    pub indirect: bool,
+}
+
+/// A subroutine, or an entry point.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Function {
+    pub entry: u16,
+    /// The blocks reachable from the entry without calling another function.
+    pub blocks: BTreeSet<u16>,
+    pub calls: BTreeSet<u16>,
+    pub callers: BTreeSet<u16>,
+}
+
+/// Recursive descent disassembly of a memory image.
+/// Starting from the entry points, only the instructions control can actually reach are decoded, following branches,
+/// jumps and subroutine calls, so data mixed in with the code isn't mistaken for instructions.
+/// Jumps through pointers can't be followed, code only reached that way shows up as data.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Analysis {
+    pub variant: Variant,
+    /// The address of the first byte of `memory`.
+    pub origin: u16,
+    pub memory: Vec<u8>,
+    /// The instructions found, by address.
+    pub instructions: BTreeMap<u16, Instruction>,
+    pub blocks: BTreeMap<u16, Block>,
+    /// The call graph, by entry point.
+    pub functions: BTreeMap<u16, Function>,
+    /// The names used in the listing and the graphs, `sub_xxxx` for functions and `loc_xxxx` for other blocks unless
+    /// named otherwise.
+    pub labels: HashMap<u16, String>,
+}
+
+impl Analysis {
+    /// Analyses `memory`, which is loaded at `origin`, starting from `entries`.
+    pub fn new(variant: Variant, memory: &[u8], origin: u16, entries: &[u16]) -> Self {
+        let len = memory.len().min(0x10000 - origin as usize);
+        let mut analysis = Self {
+            variant,
+            origin,
+            memory: memory[..len].to_vec(),
+            instructions: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            labels: HashMap::new(),
+        };
+        analysis.run(entries);
+        analysis
+    }
+
+    /// Analyses the memory in `range`, reading the bus with `peek`.
+    /// The reset vector is usually a good entry point, `bus.peek_u16(0xFFFC)`, along with the NMI and IRQ vectors.
+    pub fn from_bus(variant: Variant, bus: &impl Bus, range: std::ops::RangeInclusive<u16>, entries: &[u16]) -> Self {
+        let memory: Vec<u8> = range.clone().map(|addr| bus.peek(addr)).collect();
+        Self::new(variant, &memory, *range.start(), entries)
+    }
+
+    /// Whether `addr` is part of an instruction.
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.instructions.range(..=addr).next_back().is_some_and(|(&start, _)| addr - start < self.len(start) as u16)
+    }
+
+    fn byte(&self, addr: u16) -> Option<u8> {
+        self.memory.get(addr.wrapping_sub(self.origin) as usize).copied()
+    }
+
+    /// The length of the instruction at `addr`.
+    fn len(&self, addr: u16) -> u8 {
+        let code = self.instructions[&addr].code;
+        Metadata::of(self.variant, code).map_or(1, |metadata| metadata.bytes)
+    }
+
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        let bytes: Vec<u8> = (0..3).map_while(|i| self.byte(addr.wrapping_add(i))).collect();
+        Instruction::decode(self.variant, &bytes)
+    }
+
+    fn flow(instruction: Instruction, pc: u16) -> Flow {
+        match (instruction.opcode, instruction.addr) {
+            (Opcode::BRA, _) => Flow::Jump(instruction.branch_target(pc)),
+            (_, Address::Relative(_) | Address::ZeroRelative(..)) => Flow::Branch(instruction.branch_target(pc).unwrap()),
+            (Opcode::JMP, Address::Absolute(target)) => Flow::Jump(Some(target)),
+            (Opcode::JMP, _) => Flow::Jump(None),
+            (Opcode::JSR, Address::Absolute(target)) => Flow::Call(target),
+            (Opcode::RTS | Opcode::RTI | Opcode::BRK | Opcode::JAM | Opcode::STP, _) => Flow::Stop,
+            _ => Flow::Next,
+        }
+    }
+
+    fn run(&mut self, entries: &[u16]) {
+        let mut pending = entries.to_vec();
+        let mut functions: BTreeSet<u16> = entries.iter().copied().collect();
+        let mut leaders = functions.clone();
+
+        // find the code
+        while let Some(mut pc) = pending.pop() {
+            while !self.instructions.contains_key(&pc) {
+                let Some(instruction) = self.decode(pc) else {
+                    break;
+                };
+                self.instructions.insert(pc, instruction);
+                let next = pc.wrapping_add(self.len(pc) as u16);
+                match Self::flow(instruction, pc) {
+                    Flow::Next => (),
+                    Flow::Branch(target) => {
+                        leaders.extend([target, next]);
+                        pending.push(target);
+                    }
+                    Flow::Call(target) => {
+                        functions.insert(target);
+                        leaders.insert(target);
+                        pending.push(target);
+                    }
+                    Flow::Jump(target) => {
+                        leaders.extend(target);
+                        pending.extend(target);
+                        break;
+                    }
+                    Flow::Stop => break,
+                }
+                pc = next;
+            }
+        }
+
+        // split it into blocks
+        for &start in leaders.iter().filter(|leader| self.instructions.contains_key(leader)) {
+            let mut block = Block { start, instructions: Vec::new(), successors: Vec::new(), calls: Vec::new(), indirect: false };
+            let mut pc = start;
+            loop {
+                block.instructions.push(pc);
+                let next = pc.wrapping_add(self.len(pc) as u16);
+                match Self::flow(self.instructions[&pc], pc) {
+                    Flow::Next => (),
+                    Flow::Call(target) => block.calls.push(target),
+                    Flow::Branch(target) => {
+                        block.successors.extend([target, next]);
+                        break;
+                    }
+                    Flow::Jump(target) => {
+                        block.successors.extend(target);
+                        block.indirect = target.is_none();
+                        break;
+                    }
+                    Flow::Stop => break,
+                }
+                if leaders.contains(&next) {
+                    block.successors.push(next);
+                    break;
+                }
+                if !self.instructions.contains_key(&next) {
+                    break;
+                }
+                pc = next;
+            }
+            self.blocks.insert(start, block);
+        }
+
+        // and group the blocks into functions
+        for &entry in &functions {
+            let mut function = Function { entry, ..Function::default() };
+            let mut pending = vec![entry];
+            while let Some(start) = pending.pop() {
+                let Some(block) = self.blocks.get(&start) else {
+                    continue;
+                };
+                if !function.blocks.insert(start) {
+                    continue;
+                }
+                function.calls.extend(&block.calls);
+                // jumping to another function is a tail call
+                pending.extend(block.successors.iter().filter(|successor| !functions.contains(successor)));
+            }
+            self.functions.insert(entry, function);
+        }
+        let calls: Vec<(u16, u16)> =
+            self.functions.values().flat_map(|function| function.calls.iter().map(|&callee| (function.entry, callee))).collect();
+        for (caller, callee) in calls {
+            if let Some(function) = self.functions.get_mut(&callee) {
+                function.callers.insert(caller);
+            }
+        }
+
+        for &start in self.blocks.keys() {
+            let prefix = if functions.contains(&start) { "sub" } else { "loc" };
+            self.labels.insert(start, format!("{prefix}_{start:04x}"));
+        }
+    }
+
+    fn name(&self, addr: u16) -> String {
+        self.labels.get(&addr).cloned().unwrap_or_else(|| format!("0x{addr:04x}"))
+    }
+
+    fn names(&self, addrs: &BTreeSet<u16>) -> String {
+        addrs.iter().map(|&addr| self.name(addr)).collect::<Vec<_>>().join(", ")
+    }
+
+    /// A listing of the whole image, with the code split into functions and blocks and the data as `#d8` directives.
+    pub fn listing(&self) -> String {
+        let disassembler = Disassembler { variant: self.variant, labels: self.labels.clone() };
+        let mut listing = String::new();
+        let mut offset = 0;
+        while offset < self.memory.len() {
+            let addr = self.origin.wrapping_add(offset as u16);
+            if !self.instructions.contains_key(&addr) {
+                // up to 8 bytes of data per line, until the next instruction
+                let len = (1..8).take_while(|&i| {
+                    offset + i < self.memory.len() && !self.instructions.contains_key(&addr.wrapping_add(i as u16))
+                });
+                let len = len.count() + 1;
+                let bytes: Vec<String> = self.memory[offset..offset + len].iter().map(|byte| format!("0x{byte:02x}")).collect();
+                writeln!(listing, "0x{addr:04x}            #d8 {}", bytes.join(", ")).unwrap();
+                offset += len;
+                continue;
+            }
+            if let Some(function) = self.functions.get(&addr) {
+                writeln!(listing).unwrap();
+                if function.callers.is_empty() {
+                    writeln!(listing, "; entry point").unwrap();
+                } else {
+                    writeln!(listing, "; called by {}", self.names(&function.callers)).unwrap();
+                }
+                if !function.calls.is_empty() {
+                    writeln!(listing, "; calls {}", self.names(&function.calls)).unwrap();
+                }
+            }
+            let line = disassembler.line(addr, &self.memory[offset..]);
+            let indirect = self.blocks.values().any(|block| block.indirect && block.instructions.last() == Some(&addr));
+            if indirect {
+                writeln!(listing, "{line} ; indirect, not followed").unwrap();
+            } else {
+                writeln!(listing, "{line}").unwrap();
+            }
+            offset += line.bytes.len();
+        }
+        listing
+    }
+
+    /// The control flow graph in Graphviz DOT, a node per block holding its code.
+    pub fn cfg_dot(&self) -> String {
+        let disassembler = Disassembler { variant: self.variant, labels: self.labels.clone() };
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace]\n");
+        for block in self.blocks.values() {
+            let mut label = format!("{}:\\l", self.name(block.start));
+            for &pc in &block.instructions {
+                let text = disassembler.format(self.instructions[&pc], Some(pc));
+                write!(label, "{}\\l", text.replace('"', "\\\"")).unwrap();
+            }
+            writeln!(dot, "    \"{}\" [label=\"{label}\"]", self.name(block.start)).unwrap();
+            for &successor in &block.successors {
+                writeln!(dot, "    \"{}\" -> \"{}\"", self.name(block.start), self.name(successor)).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The call graph in Graphviz DOT.
+    pub fn calls_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box fontname=monospace]\n");
+        for function in self.functions.values() {
+            writeln!(dot, "    \"{}\"", self.name(function.entry)).unwrap();
+            for &callee in &function.calls {
+                writeln!(dot, "    \"{}\" -> \"{}\"", self.name(function.entry), self.name(callee)).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,49 @@
+; Assembled by build.rs with customasm and by the built-in assembler, the tests check they give the same bytes.
+; customasm doesn't know `*=`, so only `#addr` moves the origin here.
+
+SCREEN = 0x0200
+POINTER = 0x10
+TOP = POINTER + 0x10
+
+#addr 0x00f4
+zero_page:
+    lda forward         ; still in the zero page after the first pass
+    lda forward
+    lda forward
+forward:
+    lda later           ; pushed out of the zero page
+    lda later
+    sta later, x
+    ldx later, y
+later:
+    stx <TOP, y
+    lda (POINTER), y
+    sta (POINTER, x)
+    lda (POINTER + 1)   ; just brackets around the address
+    jmp (vector)
+    jmp main
+    #d8 1, -1, 0xff
+
+#addr 0x0400
+main:
+    LDA #0x03
+    lda #-1
+    lda #main[15:8]
+    lda #(1 + 2) * 3
+    lda #0xf0 & 0x3c | 1 << 6
+    sta SCREEN + 1
+    asl a
+    ror <POINTER, x
+    .loop:
+        dex
+        bne .loop
+        ..inner:
+            iny
+            beq ..inner
+        bcc .done
+        jsr zero_page
+    .done:
+        bmi $ + 2
+        rts
+vector:
+    #d8 main[7:0], main >> 8
@@ -0,0 +1,668 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+use crate::{Address, Instruction, Opcode, Variant};
+
+impl Instruction {
+    /// Finds the opcode byte for `opcode` with the addressing mode of `addr`, returns None if the variant doesn't have
+    /// it. Where several bytes decode to the same instruction the documented one is used, or the lowest.
+    pub fn new(variant: Variant, opcode: Opcode, addr: Address) -> Option<Instruction> {
+        let mode = std::mem::discriminant(&addr);
+        let matches = |code: &u8| {
+            Instruction::decode_opcode(variant, *code)
+                .is_some_and(|instruction| instruction.opcode == opcode && std::mem::discriminant(&instruction.addr) == mode)
+        };
+        // the documented opcodes mean the same thing on every variant
+        let documented = |code: &u8| Instruction::decode_opcode(Variant::Nmos, *code) == Instruction::decode_opcode(Variant::Wdc65C02, *code);
+        let code = (0..=255).filter(matches).find(documented).or_else(|| (0..=255).find(matches))?;
+        Some(Instruction { opcode, addr, code })
+    }
+
+    /// The machine code of the instruction, the inverse of `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.code];
+        match self.addr {
+            Address::Implied | Address::Accumulator => (),
+            Address::Immediate(byte)
+            | Address::Zero(byte)
+            | Address::ZeroX(byte)
+            | Address::ZeroY(byte)
+            | Address::Relative(byte)
+            | Address::IndirectX(byte)
+            | Address::IndirectY(byte)
+            | Address::ZeroIndirect(byte) => bytes.push(byte),
+            Address::ZeroRelative(addr, offset) => bytes.extend([addr, offset]),
+            Address::Absolute(addr) | Address::AbsoluteX(addr) | Address::AbsoluteY(addr) | Address::Indirect(addr) | Address::AbsoluteIndirectX(addr) => {
+                bytes.extend(addr.to_le_bytes())
+            }
+        }
+        bytes
+    }
+}
+
+/// An error in the source, `line` counts from 1.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum AsmErrorKind {
+    /// The line couldn't be parsed.
+    Syntax,
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    /// The instruction doesn't have the addressing mode.
+    InvalidOperand,
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    /// A value doesn't fit in its operand.
+    OutOfRange(i64),
+    /// The branch target is more than 128 bytes away.
+    BranchOutOfRange(i64),
+    DivisionByZero,
+    /// The origin was moved back over code that was already assembled.
+    Backwards,
+    /// The code runs past the end of the address space.
+    Overflow,
+    /// The sizes of the instructions kept changing with the addresses of the labels.
+    NotConverged,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::Syntax => write!(f, "syntax error"),
+            AsmErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic `{mnemonic}`"),
+            AsmErrorKind::UnknownDirective(directive) => write!(f, "unknown directive `#{directive}`"),
+            AsmErrorKind::InvalidOperand => write!(f, "the instruction doesn't support the operand"),
+            AsmErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol `{name}`"),
+            AsmErrorKind::DuplicateSymbol(name) => write!(f, "`{name}` is already defined"),
+            AsmErrorKind::OutOfRange(value) => write!(f, "{value} is out of range"),
+            AsmErrorKind::BranchOutOfRange(offset) => write!(f, "the branch target is {offset} bytes away"),
+            AsmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            AsmErrorKind::Backwards => write!(f, "the origin moves backwards"),
+            AsmErrorKind::Overflow => write!(f, "the code runs past 0xffff"),
+            AsmErrorKind::NotConverged => write!(f, "the instruction sizes didn't converge"),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// The output of the assembler.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Assembly {
+    /// The address of the first byte, where the first `*=` put it, or 0.
+    pub origin: u16,
+    /// The code, any gaps left by `*=` are filled with 0.
+    pub bytes: Vec<u8>,
+    /// The labels and constants, local labels are qualified by their parents, like `main.loop`.
+    pub symbols: BTreeMap<String, i64>,
+}
+
+/// A line assembler for the syntax of `src/asm/6502.asm`, which gives the same bytes as customasm.
+///
+/// Each line holds an optional label, `name:` or `.local:`, then an instruction, a constant, `name = value`, or a
+/// directive. `#addr addr` moves the origin and `#d8 a, b, ..` emits bytes. Values are expressions over numbers,
+/// symbols and `$`, the address of the line, with customasm's operators.
+///
+/// `*= addr` moves the origin too, but it is an extension: customasm 0.11 rejects it with "no match for instruction
+/// found", so sources meant for both should stick to `#addr`.
+///
+/// Zero page addressing is used when the operand fits in a byte, `<` forces it. `(x)` is an indirect operand only for
+/// the instructions that have one. The undocumented and 65C02 instructions of the variant are accepted too.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Assembler {
+    pub variant: Variant,
+    /// Symbols defined before the source, like the labels of code already in memory.
+    pub symbols: HashMap<String, i64>,
+}
+
+/// Gives up when the sizes still change after this many passes.
+const PASSES: usize = 16;
+
+impl Assembler {
+    pub fn new(variant: Variant) -> Self {
+        Self { variant, symbols: HashMap::new() }
+    }
+
+    /// Defines a symbol the source can use.
+    pub fn symbol(mut self, name: impl Into<String>, value: i64) -> Self {
+        self.symbols.insert(name.into(), value);
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Assembly, AsmError> {
+        self.run(&parse(self.variant, source)?)
+    }
+
+    /// Assembles `text` at `pc`, to patch code in memory.
+    pub fn line(&self, text: &str, pc: u16) -> Result<Vec<u8>, AsmError> {
+        let mut items = vec![Item { line: 1, kind: Kind::Origin(Expr::Number(pc as i64)) }];
+        items.extend(parse(self.variant, text)?);
+        Ok(self.run(&items)?.bytes)
+    }
+
+    /// Lays out the code a pass at a time until the labels stop moving, like customasm.
+    /// Operands that can't be worked out yet take the size they had in the previous pass.
+    fn run(&self, items: &[Item]) -> Result<Assembly, AsmError> {
+        let mut guesses = HashMap::new();
+        let mut sizes = HashMap::new();
+        for pass in 0..PASSES {
+            let mut symbols = self.symbols.clone();
+            // the address of each item, and where the last one ends
+            let mut pcs = Vec::with_capacity(items.len() + 1);
+            let mut pc = 0;
+            for (index, item) in items.iter().enumerate() {
+                pcs.push(pc);
+                let early = |name: &str| symbols.get(name).or_else(|| guesses.get(name)).copied();
+                match &item.kind {
+                    Kind::Label(name) => define(&mut symbols, &self.symbols, name, pc, item.line)?,
+                    // like customasm, constants and origins can only use the symbols above them
+                    Kind::Constant(name, expr) => {
+                        let value = expr.eval(pc, &|name| symbols.get(name).copied()).map_err(|kind| AsmError { line: item.line, kind })?;
+                        define(&mut symbols, &self.symbols, name, value, item.line)?;
+                    }
+                    Kind::Origin(expr) => {
+                        let value = expr.eval(pc, &|name| symbols.get(name).copied()).map_err(|kind| AsmError { line: item.line, kind })?;
+                        pc = u16::try_from(value).map_err(|_| AsmError { line: item.line, kind: AsmErrorKind::OutOfRange(value) })? as i64;
+                    }
+                    Kind::Data(exprs) => pc += exprs.len() as i64,
+                    Kind::Instruction(opcode, operand) => {
+                        pc += match self.encode(*opcode, operand, pc, &early, pass == 0) {
+                            Ok(instruction) => instruction.encode().len() as i64,
+                            Err(_) => sizes.get(&index).copied().unwrap_or(0),
+                        };
+                    }
+                }
+            }
+            pcs.push(pc);
+
+            // now that every label has an address, check the guesses
+            let mut stable = true;
+            let mut error = None;
+            let mut origin = None;
+            let mut output = Vec::new();
+            let late = |name: &str| symbols.get(name).copied();
+            for (index, item) in items.iter().enumerate() {
+                let (pc, next) = (pcs[index], pcs[index + 1]);
+                let result = match &item.kind {
+                    Kind::Label(_) | Kind::Constant(..) => Ok(()),
+                    Kind::Origin(_) => {
+                        origin.get_or_insert(next);
+                        Ok(())
+                    }
+                    Kind::Data(exprs) => exprs
+                        .iter()
+                        .map(|expr| expr.eval(pc, &late).and_then(|value| byte(value, -0x80)))
+                        .collect::<Result<Vec<u8>, _>>()
+                        .map(|bytes| {
+                            origin.get_or_insert(0);
+                            output.push((item.line, pc, bytes));
+                        }),
+                    Kind::Instruction(opcode, operand) => self.encode(*opcode, operand, pc, &late, false).map(|instruction| {
+                        let bytes = instruction.encode();
+                        stable &= next == pc + bytes.len() as i64;
+                        sizes.insert(index, bytes.len() as i64);
+                        origin.get_or_insert(0);
+                        output.push((item.line, pc, bytes));
+                    }),
+                };
+                if let Err(kind) = result {
+                    error.get_or_insert(AsmError { line: item.line, kind });
+                }
+            }
+            // an error can also come from a guess that was wrong, it's only real once the labels stop moving
+            if !stable || (error.is_some() && symbols != guesses) {
+                guesses = symbols;
+                continue;
+            }
+            if let Some(error) = error {
+                return Err(error);
+            }
+            return Self::link(origin.unwrap_or(0), output, symbols);
+        }
+        Err(AsmError { line: items.last().map_or(0, |item| item.line), kind: AsmErrorKind::NotConverged })
+    }
+
+    /// Puts the pieces of code together into one image, starting at `origin`.
+    fn link(origin: i64, output: Vec<(usize, i64, Vec<u8>)>, symbols: HashMap<String, i64>) -> Result<Assembly, AsmError> {
+        let mut bytes = Vec::new();
+        for (line, pc, code) in output {
+            if pc < origin + bytes.len() as i64 {
+                return Err(AsmError { line, kind: AsmErrorKind::Backwards });
+            }
+            if pc + code.len() as i64 > 0x10000 {
+                return Err(AsmError { line, kind: AsmErrorKind::Overflow });
+            }
+            bytes.resize((pc - origin) as usize, 0);
+            bytes.extend(code);
+        }
+        Ok(Assembly { origin: origin as u16, bytes, symbols: symbols.into_iter().collect() })
+    }
+
+    /// Picks the addressing mode for the operand, `lookup` gives the values of the symbols.
+    /// With `unknown` set, undefined symbols can take any value and the smallest encoding is used, which is how the
+    /// first pass sizes forward references.
+    fn encode(&self, opcode: Opcode, operand: &Operand, pc: i64, lookup: &dyn Fn(&str) -> Option<i64>, unknown: bool) -> Result<Instruction, AsmErrorKind> {
+        let has = |addr: Address| Instruction::new(self.variant, opcode, addr).is_some();
+        let value = |expr: &Expr| match expr.eval(pc, lookup) {
+            Err(AsmErrorKind::UndefinedSymbol(_)) if unknown => Ok(None),
+            result => result.map(Some),
+        };
+        let zero = |expr: &Expr| value(expr)?.map_or(Ok(0), |value| byte(value, 0));
+        let word = |expr: &Expr| value(expr)?.map_or(Ok(0), |value| u16::try_from(value).map_err(|_| AsmErrorKind::OutOfRange(value)));
+        let offset = |expr: &Expr, len: i64| {
+            if value(expr)?.is_none() {
+                return Ok(0);
+            }
+            let offset = word(expr)? as i64 - pc - len;
+            i8::try_from(offset).map(|offset| offset as u8).map_err(|_| AsmErrorKind::BranchOutOfRange(offset))
+        };
+        // the zero page mode if the value fits in it
+        let sized = |expr: &Expr, forced: bool, zero_page: fn(u8) -> Address, absolute: fn(u16) -> Address| {
+            let fits = value(expr)?.is_none_or(|value| (0..=0xff).contains(&value));
+            if forced || (has(zero_page(0)) && fits) {
+                zero(expr).map(zero_page)
+            } else {
+                word(expr).map(absolute)
+            }
+        };
+        let direct = |addr: &Expr, forced: bool, index: Option<&Index>| -> Result<Address, AsmErrorKind> {
+            match index {
+                None if !forced && has(Address::Relative(0)) => Ok(Address::Relative(offset(addr, 2)?)),
+                None => sized(addr, forced, Address::Zero, Address::Absolute),
+                Some(Index::X) => sized(addr, forced, Address::ZeroX, Address::AbsoluteX),
+                Some(Index::Y) => sized(addr, forced, Address::ZeroY, Address::AbsoluteY),
+                Some(Index::Branch(target)) => Ok(Address::ZeroRelative(zero(addr)?, offset(target, 3)?)),
+            }
+        };
+        let addr = match operand {
+            Operand::None => Address::Implied,
+            Operand::Accumulator => Address::Accumulator,
+            Operand::Immediate(expr) => Address::Immediate(value(expr)?.map_or(Ok(0), |value| byte(value, -0x80))?),
+            Operand::Direct { addr, zero, index } => direct(addr, *zero, index.as_ref())?,
+            Operand::IndirectX(expr) if has(Address::AbsoluteIndirectX(0)) => Address::AbsoluteIndirectX(word(expr)?),
+            Operand::IndirectX(expr) => Address::IndirectX(zero(expr)?),
+            Operand::IndirectY(expr) if has(Address::IndirectY(0)) => Address::IndirectY(zero(expr)?),
+            Operand::Indirect(expr) if has(Address::Indirect(0)) => Address::Indirect(word(expr)?),
+            Operand::Indirect(expr) if has(Address::ZeroIndirect(0)) => Address::ZeroIndirect(zero(expr)?),
+            // otherwise the brackets are just part of the expression
+            Operand::IndirectY(expr) => direct(expr, false, Some(&Index::Y))?,
+            Operand::Indirect(expr) => direct(expr, false, None)?,
+        };
+        Instruction::new(self.variant, opcode, addr).ok_or(AsmErrorKind::InvalidOperand)
+    }
+}
+
+/// Checks `value` fits in a byte, `min` is -0x80 where signed values are allowed too.
+fn byte(value: i64, min: i64) -> Result<u8, AsmErrorKind> {
+    if (min..=0xff).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(AsmErrorKind::OutOfRange(value))
+    }
+}
+
+fn define(symbols: &mut HashMap<String, i64>, predefined: &HashMap<String, i64>, name: &str, value: i64, line: usize) -> Result<(), AsmError> {
+    if symbols.insert(name.to_string(), value).is_some() && !predefined.contains_key(name) {
+        return Err(AsmError { line, kind: AsmErrorKind::DuplicateSymbol(name.to_string()) });
+    }
+    Ok(())
+}
+
+struct Item {
+    line: usize,
+    kind: Kind,
+}
+
+enum Kind {
+    Label(String),
+    Constant(String, Expr),
+    Origin(Expr),
+    Data(Vec<Expr>),
+    Instruction(Opcode, Operand),
+}
+
+/// The operand as written, before the addressing mode is picked.
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    /// `addr`, `addr, x`, `addr, y` or, for BBR and BBS, `addr, target`. `zero` is set by `<`.
+    Direct { addr: Expr, zero: bool, index: Option<Index> },
+    /// `(addr, x)`
+    IndirectX(Expr),
+    /// `(addr), y`
+    IndirectY(Expr),
+    /// `(addr)`
+    Indirect(Expr),
+}
+
+enum Index {
+    X,
+    Y,
+    Branch(Expr),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Op {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    /// `$`, the address of the line.
+    Pc,
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    /// `value[hi:lo]`, the bits from `hi` down to `lo`.
+    Slice(Box<Expr>, u32, u32),
+}
+
+impl Expr {
+    fn eval(&self, pc: i64, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, AsmErrorKind> {
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| AsmErrorKind::UndefinedSymbol(name.clone()))?,
+            Expr::Pc => pc,
+            Expr::Neg(expr) => expr.eval(pc, lookup)?.wrapping_neg(),
+            Expr::Not(expr) => !expr.eval(pc, lookup)?,
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(pc, lookup)?, right.eval(pc, lookup)?);
+                match op {
+                    Op::Or => left | right,
+                    Op::Xor => left ^ right,
+                    Op::And => left & right,
+                    Op::Shl => left.checked_shl(right as u32).unwrap_or(0),
+                    Op::Shr => left.checked_shr(right as u32).unwrap_or(0),
+                    Op::Add => left.wrapping_add(right),
+                    Op::Sub => left.wrapping_sub(right),
+                    Op::Mul => left.wrapping_mul(right),
+                    Op::Div => left.checked_div(right).ok_or(AsmErrorKind::DivisionByZero)?,
+                    Op::Rem => left.checked_rem(right).ok_or(AsmErrorKind::DivisionByZero)?,
+                }
+            }
+            Expr::Slice(expr, hi, lo) => (expr.eval(pc, lookup)? >> lo) & ((1 << (hi - lo + 1)) - 1),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum Token {
+    Number(i64),
+    /// A name, with the dots in front of local labels.
+    Name(String),
+    Punct(&'static str),
+}
+
+/// Longest first, so `<<` isn't read as two `<`.
+const PUNCTUATION: [&str; 22] = ["<<", ">>", "*=", "#", "$", "(", ")", "[", "]", ",", ":", "=", "<", "+", "-", "*", "/", "%", "&", "|", "^", "!"];
+
+fn tokenize(line: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let len = if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let digits = rest[..len].replace('_', "");
+            let value = match digits.get(..2) {
+                Some("0x") => i64::from_str_radix(&digits[2..], 16),
+                Some("0b") => i64::from_str_radix(&digits[2..], 2),
+                _ => digits.parse(),
+            };
+            tokens.push(Token::Number(value.ok()?));
+            len
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let dots = rest.find(|c| c != '.').unwrap_or(rest.len());
+            let len = rest[dots..].find(|c: char| !c.is_alphanumeric() && c != '_').map_or(rest.len(), |len| dots + len);
+            if len == dots {
+                return None;
+            }
+            tokens.push(Token::Name(rest[..len].to_string()));
+            len
+        } else if let Some(punct) = PUNCTUATION.iter().find(|punct| rest.starts_with(**punct)) {
+            tokens.push(Token::Punct(punct));
+            punct.len()
+        } else {
+            return None;
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+fn parse(variant: Variant, source: &str) -> Result<Vec<Item>, AsmError> {
+    let mnemonics: HashMap<String, Opcode> = (0..=255)
+        .filter_map(|code| Instruction::decode_opcode(variant, code))
+        .map(|instruction| (instruction.opcode.to_string(), instruction.opcode))
+        .collect();
+    let mut items = Vec::new();
+    // the labels each level of local labels hangs off
+    let mut scopes: Vec<String> = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| AsmError { line, kind };
+        let text = text.split(';').next().unwrap();
+        let tokens = tokenize(text).ok_or(error(AsmErrorKind::Syntax))?;
+        let mut tokens = tokens.as_slice();
+
+        // labels and constants
+        if let [Token::Name(name), Token::Punct(punct @ (":" | "=")), rest @ ..] = tokens {
+            let dots = name.len() - name.trim_start_matches('.').len();
+            if dots > scopes.len() {
+                return Err(error(AsmErrorKind::Syntax));
+            }
+            scopes.truncate(dots);
+            scopes.push(name[dots..].to_string());
+            let name = scopes.join(".");
+            if *punct == "=" {
+                let mut parser = Parser { scopes: &scopes, tokens: rest };
+                items.push(Item { line, kind: Kind::Constant(name, parser.all(Parser::expr).ok_or(error(AsmErrorKind::Syntax))?) });
+                continue;
+            }
+            items.push(Item { line, kind: Kind::Label(name) });
+            tokens = rest;
+        }
+
+        let mut parser = Parser { scopes: &scopes, tokens: &[] };
+        let kind = match tokens {
+            [] => continue,
+            [Token::Punct("*="), rest @ ..] => {
+                parser.tokens = rest;
+                parser.all(Parser::expr).map(Kind::Origin)
+            }
+            [Token::Punct("#"), Token::Name(directive), rest @ ..] => {
+                parser.tokens = rest;
+                match directive.as_str() {
+                    "addr" => parser.all(Parser::expr).map(Kind::Origin),
+                    "d8" => parser.all(Parser::list).map(Kind::Data),
+                    _ => return Err(error(AsmErrorKind::UnknownDirective(directive.clone()))),
+                }
+            }
+            [Token::Name(mnemonic), rest @ ..] => {
+                let opcode = *mnemonics.get(&mnemonic.to_lowercase()).ok_or_else(|| error(AsmErrorKind::UnknownMnemonic(mnemonic.clone())))?;
+                parser.tokens = rest;
+                parser.operand().map(|operand| Kind::Instruction(opcode, operand))
+            }
+            _ => None,
+        };
+        items.push(Item { line, kind: kind.ok_or(error(AsmErrorKind::Syntax))? });
+    }
+    Ok(items)
+}
+
+/// A recursive descent parser over the tokens of a line, returning None on syntax errors.
+struct Parser<'a> {
+    scopes: &'a [String],
+    tokens: &'a [Token],
+}
+
+impl Parser<'_> {
+    fn next(&self) -> Option<&Token> {
+        self.tokens.first()
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = matches!(self.next(), Some(Token::Punct(next)) if *next == punct);
+        if found {
+            self.tokens = &self.tokens[1..];
+        }
+        found
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        let found = matches!(self.next(), Some(Token::Name(next)) if next.eq_ignore_ascii_case(name));
+        if found {
+            self.tokens = &self.tokens[1..];
+        }
+        found
+    }
+
+    /// Runs `parse` over the rest of the tokens.
+    fn all<T>(&mut self, parse: fn(&mut Self) -> Option<T>) -> Option<T> {
+        let value = parse(self)?;
+        self.tokens.is_empty().then_some(value)
+    }
+
+    fn list(&mut self) -> Option<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.eat(",") {
+            exprs.push(self.expr()?);
+        }
+        Some(exprs)
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        if self.tokens.is_empty() {
+            return Some(Operand::None);
+        }
+        if self.tokens.len() == 1 && self.eat_name("a") {
+            return Some(Operand::Accumulator);
+        }
+        if self.eat("#") {
+            return self.all(Self::expr).map(Operand::Immediate);
+        }
+        // `(..)` is an indirect operand when the brackets go around the whole address
+        if self.next() == Some(&Token::Punct("(")) {
+            let mut depth = 0;
+            let close = self.tokens.iter().position(|token| {
+                match token {
+                    Token::Punct("(") => depth += 1,
+                    Token::Punct(")") => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            })?;
+            let rest = &self.tokens[close + 1..];
+            let y = matches!(rest, [Token::Punct(","), Token::Name(y)] if y.eq_ignore_ascii_case("y"));
+            if rest.is_empty() || y {
+                let mut inner = Parser { scopes: self.scopes, tokens: &self.tokens[1..close] };
+                let addr = inner.expr()?;
+                self.tokens = &[];
+                if inner.tokens.is_empty() {
+                    return Some(if y { Operand::IndirectY(addr) } else { Operand::Indirect(addr) });
+                }
+                return (!y && inner.eat(",") && inner.eat_name("x") && inner.tokens.is_empty()).then_some(Operand::IndirectX(addr));
+            }
+        }
+        let zero = self.eat("<");
+        let addr = self.expr()?;
+        let index = if self.eat(",") {
+            Some(if self.eat_name("x") {
+                Index::X
+            } else if self.eat_name("y") {
+                Index::Y
+            } else {
+                Index::Branch(self.expr()?)
+            })
+        } else {
+            None
+        };
+        self.tokens.is_empty().then_some(Operand::Direct { addr, zero, index })
+    }
+
+    /// Parses the binary operators, loosest first like customasm.
+    fn expr(&mut self) -> Option<Expr> {
+        const LEVELS: [&[(&str, Op)]; 6] = [
+            &[("|", Op::Or)],
+            &[("^", Op::Xor)],
+            &[("&", Op::And)],
+            &[("<<", Op::Shl), (">>", Op::Shr)],
+            &[("+", Op::Add), ("-", Op::Sub)],
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+        ];
+        self.binary(&LEVELS)
+    }
+
+    fn binary(&mut self, levels: &[&[(&str, Op)]]) -> Option<Expr> {
+        let Some((ops, tighter)) = levels.split_first() else {
+            return self.slice();
+        };
+        let mut left = self.binary(tighter)?;
+        'outer: loop {
+            for (punct, op) in *ops {
+                if self.eat(punct) {
+                    left = Expr::Binary(*op, Box::new(left), Box::new(self.binary(tighter)?));
+                    continue 'outer;
+                }
+            }
+            return Some(left);
+        }
+    }
+
+    fn slice(&mut self) -> Option<Expr> {
+        let expr = self.unary()?;
+        if !self.eat("[") {
+            return Some(expr);
+        }
+        let [Token::Number(hi), Token::Punct(":"), Token::Number(lo), Token::Punct("]"), ..] = self.tokens else {
+            return None;
+        };
+        self.tokens = &self.tokens[4..];
+        (lo <= hi && *hi < 63).then(|| Expr::Slice(Box::new(expr), *hi as u32, *lo as u32))
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        if self.eat("-") {
+            return Some(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Some(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.expr()?;
+            return self.eat(")").then_some(expr);
+        }
+        if self.eat("$") {
+            return Some(Expr::Pc);
+        }
+        let token = self.next()?.clone();
+        self.tokens = &self.tokens[1..];
+        match token {
+            Token::Number(value) => Some(Expr::Number(value)),
+            // local names are looked up under the labels they hang off
+            Token::Name(name) => {
+                let dots = name.len() - name.trim_start_matches('.').len();
+                let scopes = self.scopes.get(..dots)?;
+                Some(Expr::Symbol(scopes.iter().map(String::as_str).chain([&name[dots..]]).collect::<Vec<_>>().join(".")))
+            }
+            Token::Punct(_) => None,
+        }
+    }
+}
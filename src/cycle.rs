@@ -0,0 +1,534 @@
+use crate::instruction::{Access, Address, Instruction, Metadata, Opcode};
+use crate::{BrkPolicy, Bus, Clock, Cpu, Error, Interrupt, SnapshotError, Stop, Variant};
+
+/// The progress of the instruction `tick` is in the middle of.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub(crate) struct Cycle {
+    /// The number of cycles of the current instruction that have run, 0 between instructions.
+    n: u8,
+    /// The address of the opcode, or where the interrupt was taken.
+    pc: u16,
+    /// The instruction being executed, its operands are left as 0 and fetched into the fields below.
+    instruction: Option<Instruction>,
+    /// The interrupt being taken instead of an instruction.
+    interrupt: Option<Interrupt>,
+    /// The cycle the effective address became known on, 0 while it's being worked out.
+    base: u8,
+    /// The effective address.
+    addr: u16,
+    /// The zero page pointer of the indirect modes.
+    pointer: u8,
+    /// The data latch, holds the value of read-modify-write instructions and the low byte of jump targets.
+    value: u8,
+    /// Whether indexing crossed a page, so the high byte of the address has to be fixed.
+    crossed: bool,
+    /// The interrupt the lines asked for on the second-to-last cycle, taken once the instruction has finished.
+    /// Kept between instructions.
+    poll: Option<Interrupt>,
+}
+
+impl Cycle {
+    /// The length of the state written by `save`.
+    pub(crate) const SIZE: usize = 13;
+
+    /// Writes the progress for a save state, the instruction as its opcode.
+    pub(crate) fn save(&self, out: &mut Vec<u8>) {
+        out.push(self.n);
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        match self.instruction {
+            Some(instruction) => out.extend_from_slice(&[1, instruction.code]),
+            None => out.extend_from_slice(&[0, 0]),
+        }
+        out.push(Self::save_interrupt(self.interrupt));
+        out.push(self.base);
+        out.extend_from_slice(&self.addr.to_le_bytes());
+        out.extend_from_slice(&[self.pointer, self.value, self.crossed as u8, Self::save_interrupt(self.poll)]);
+    }
+
+    fn save_interrupt(interrupt: Option<Interrupt>) -> u8 {
+        match interrupt {
+            None => 0,
+            Some(Interrupt::Reset) => 1,
+            Some(Interrupt::Nmi) => 2,
+            Some(Interrupt::Irq) => 3,
+        }
+    }
+
+    fn load_interrupt(byte: u8) -> Result<Option<Interrupt>, SnapshotError> {
+        Ok(match byte {
+            0 => None,
+            1 => Some(Interrupt::Reset),
+            2 => Some(Interrupt::Nmi),
+            3 => Some(Interrupt::Irq),
+            _ => return Err(SnapshotError::Invalid("unknown interrupt in progress")),
+        })
+    }
+
+    /// Reads the progress written by `save`, the opcode is decoded for `variant`.
+    pub(crate) fn load(variant: Variant, data: &[u8; Self::SIZE]) -> Result<Self, SnapshotError> {
+        let instruction = match data[3] {
+            0 => None,
+            _ => Some(Instruction::decode_opcode(variant, data[4]).ok_or(SnapshotError::Invalid("unknown opcode in progress"))?),
+        };
+        Ok(Self {
+            n: data[0],
+            pc: u16::from_le_bytes([data[1], data[2]]),
+            instruction,
+            interrupt: Self::load_interrupt(data[5])?,
+            base: data[6],
+            addr: u16::from_le_bytes([data[7], data[8]]),
+            pointer: data[9],
+            value: data[10],
+            crossed: data[11] != 0,
+            poll: Self::load_interrupt(data[12])?,
+        })
+    }
+}
+
+/// What a cycle did to the current instruction.
+enum Progress {
+    Next,
+    /// The instruction is finished, with why the emulation should stop if it should.
+    Done(Option<Stop>),
+}
+
+impl<B: Bus, C: Clock> Cpu<B, C> {
+    /// Runs a single clock cycle, making the one bus access the real chip makes in it.
+    /// Instructions are spread over several ticks, dummy reads and the extra write of read-modify-write instructions included,
+    /// so devices see the same bus traffic as on hardware and the interrupt lines can change between any two cycles.
+    /// Like on the real chip, the interrupt lines are polled on the second-to-last cycle of each instruction and the
+    /// interrupt is taken once the instruction has finished, a line that becomes active on the last cycle waits for the
+    /// next instruction.
+    /// Returns why the emulation should stop once the instruction that stops it has finished.
+    /// Accesses the bus refused are reported once the instruction has finished, see `FaultPolicy`.
+    /// `step` and `execute` can be used alongside `tick`, but only while `at_boundary` returns true.
+    pub fn tick(&mut self) -> Result<Option<Stop>, Error> {
+        if self.stopped {
+            return Ok(Some(Stop::Stp));
+        }
+        let progress = self.run_cycle();
+        self.cycles += 1;
+        self.clock.sync(self.cycles);
+        match progress {
+            Ok(Progress::Next) => {
+                self.tick_state.poll = self.requested_interrupt();
+                Ok(None)
+            }
+            Ok(Progress::Done(stop)) => {
+                let pc = self.tick_state.pc;
+                // an instruction without a second-to-last cycle polls on its only one
+                let poll = if self.tick_state.n == 1 { self.requested_interrupt() } else { self.tick_state.poll };
+                self.tick_state = Cycle { poll, ..Cycle::default() };
+                self.check_fault(pc)?;
+                Ok(stop)
+            }
+            Err(error) => {
+                self.tick_state = Cycle::default();
+                Err(error)
+            }
+        }
+    }
+
+    /// Whether `tick` is between instructions, so the next cycle fetches an opcode or starts an interrupt.
+    pub fn at_boundary(&self) -> bool {
+        self.tick_state.n == 0
+    }
+
+    fn run_cycle(&mut self) -> Result<Progress, Error> {
+        let n = self.tick_state.n + 1;
+        self.tick_state.n = n;
+        if n == 1 {
+            return self.fetch_cycle();
+        }
+        if let Some(interrupt) = self.tick_state.interrupt {
+            if n == 2 {
+                // the opcode fetch is repeated and thrown away
+                self.bus.load(self.pc);
+                return Ok(Progress::Next);
+            }
+            let status = (self.status & !0b00010000) | 0b00100000;
+            return Ok(self.push_cycle(n, status, interrupt.vector()));
+        }
+        let instruction = self.tick_state.instruction.expect("an instruction is in progress");
+        let access = instruction.access();
+        match access {
+            Access::Control => self.control_cycle(instruction, n),
+            Access::Implied => {
+                self.bus.load(self.pc);
+                self.implied_op(instruction.opcode);
+                Ok(Progress::Done(None))
+            }
+            Access::Modify if instruction.addr == Address::Accumulator => {
+                self.bus.load(self.pc);
+                self.accumulator = self.modify_op(instruction, self.accumulator);
+                Ok(Progress::Done(None))
+            }
+            _ => {
+                if self.tick_state.base == 0 && self.address_cycle(instruction, n)? {
+                    return Ok(Progress::Next);
+                }
+                Ok(self.access_cycle(instruction, access, n - self.tick_state.base))
+            }
+        }
+    }
+
+    /// The interrupt the lines ask for.
+    fn requested_interrupt(&self) -> Option<Interrupt> {
+        if self.nmi_pending {
+            Some(Interrupt::Nmi)
+        } else if self.irq_line && !self.interrupt_disable() {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        }
+    }
+
+    /// Fetches an opcode, or starts the interrupt polled during the last instruction instead.
+    fn fetch_cycle(&mut self) -> Result<Progress, Error> {
+        self.tick_state.pc = self.pc;
+        let mut poll = self.tick_state.poll.take();
+        if self.waiting {
+            if !self.nmi_pending && !self.irq_line {
+                // nothing is on the bus while waiting
+                return Ok(Progress::Done(Some(Stop::Wai)));
+            }
+            self.waiting = false;
+            poll = self.requested_interrupt();
+        }
+        // `step` may have serviced the interrupt or the line may have been released since
+        let interrupt = match poll {
+            Some(Interrupt::Nmi) if self.nmi_pending => {
+                self.nmi_pending = false;
+                Some(Interrupt::Nmi)
+            }
+            Some(Interrupt::Irq) if self.irq_line => Some(Interrupt::Irq),
+            _ => None,
+        };
+        if interrupt.is_some() {
+            self.bus.load(self.pc);
+            self.tick_state.interrupt = interrupt;
+            return Ok(Progress::Next);
+        }
+
+        let opcode = self.load_pc();
+        let instruction = Instruction::decode_opcode(self.variant, opcode).ok_or(Error::IllegalOpcode { pc: self.pc.wrapping_sub(1), opcode })?;
+        self.tick_state.instruction = Some(instruction);
+        Ok(match instruction.opcode {
+            Opcode::JAM => {
+                self.pc = self.pc.wrapping_sub(1);
+                Progress::Done(Some(Stop::Jam))
+            }
+            Opcode::BRK if self.brk_policy == BrkPolicy::Halt => {
+                self.pc = self.pc.wrapping_sub(1);
+                Progress::Done(Some(Stop::Brk))
+            }
+            // the unused opcodes of the 65C02 are single cycle NOPs
+            Opcode::NOP if instruction.addr == Address::Implied && self.variant.is_cmos() && opcode != 0xEA => Progress::Done(None),
+            _ => Progress::Next,
+        })
+    }
+
+    /// Cycles 3 to 7 of BRK and the hardware interrupts.
+    fn push_cycle(&mut self, n: u8, status: u8, vector: u16) -> Progress {
+        match n {
+            3 => self.push((self.pc >> 8) as u8),
+            4 => self.push(self.pc as u8),
+            5 => {
+                self.push(status);
+                self.set_interrupt_disable(true);
+                if self.variant.is_cmos() {
+                    self.set_decimal(false);
+                }
+            }
+            6 => self.tick_state.value = self.bus.load(vector),
+            _ => {
+                self.pc = u16::from_le_bytes([self.tick_state.value, self.bus.load(vector.wrapping_add(1))]);
+                return Progress::Done(None);
+            }
+        }
+        Progress::Next
+    }
+
+    /// Works out the effective address, returns false if it's already known and this cycle accesses the operand.
+    fn address_cycle(&mut self, instruction: Instruction, n: u8) -> Result<bool, Error> {
+        let state = self.tick_state;
+        match (instruction.addr, n) {
+            (Address::Immediate(_), _) => {
+                self.tick_state.addr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                self.tick_state.base = n - 1;
+                return Ok(false);
+            }
+            (Address::Zero(_), 2) => self.tick_state.addr = self.load_pc() as u16,
+            (Address::Absolute(_) | Address::AbsoluteX(_) | Address::AbsoluteY(_), 2) => {
+                self.tick_state.addr = self.load_pc() as u16;
+                return Ok(true);
+            }
+            (Address::ZeroX(_) | Address::ZeroY(_) | Address::IndirectX(_) | Address::IndirectY(_) | Address::ZeroIndirect(_), 2) => {
+                self.tick_state.pointer = self.load_pc();
+                return Ok(true);
+            }
+            (Address::ZeroX(_), 3) => {
+                self.bus.load(state.pointer as u16);
+                self.tick_state.addr = state.pointer.wrapping_add(self.x) as u16;
+            }
+            (Address::ZeroY(_), 3) => {
+                self.bus.load(state.pointer as u16);
+                self.tick_state.addr = state.pointer.wrapping_add(self.y) as u16;
+            }
+            (Address::Absolute(_), 3) => self.tick_state.addr |= (self.load_pc() as u16) << 8,
+            (Address::AbsoluteX(_), 3) => {
+                let base = state.addr | (self.load_pc() as u16) << 8;
+                self.index(base, self.x);
+                return Ok(true);
+            }
+            (Address::AbsoluteY(_), 3) => {
+                let base = state.addr | (self.load_pc() as u16) << 8;
+                self.index(base, self.y);
+                return Ok(true);
+            }
+            (Address::IndirectX(_), 3) => {
+                self.bus.load(state.pointer as u16);
+                self.tick_state.pointer = state.pointer.wrapping_add(self.x);
+                return Ok(true);
+            }
+            (Address::IndirectX(_), 4) => {
+                self.tick_state.addr = self.bus.load(state.pointer as u16) as u16;
+                return Ok(true);
+            }
+            (Address::IndirectX(_), 5) | (Address::ZeroIndirect(_), 4) => {
+                self.tick_state.addr |= (self.bus.load(state.pointer.wrapping_add(1) as u16) as u16) << 8;
+            }
+            (Address::IndirectY(_) | Address::ZeroIndirect(_), 3) => {
+                self.tick_state.addr = self.bus.load(state.pointer as u16) as u16;
+                return Ok(true);
+            }
+            (Address::IndirectY(_), 4) => {
+                let base = state.addr | (self.bus.load(state.pointer.wrapping_add(1) as u16) as u16) << 8;
+                self.index(base, self.y);
+                return Ok(true);
+            }
+            (Address::AbsoluteX(_) | Address::AbsoluteY(_), 4) | (Address::IndirectY(_), 5) => {
+                // instructions with a page crossing penalty skip the fixup when there's nothing to fix
+                if !state.crossed && self.metadata(instruction, self.tick_state.pc)?.penalty.page {
+                    self.tick_state.base = n - 1;
+                    return Ok(false);
+                }
+                if state.crossed && self.variant.is_cmos() {
+                    // the 65C02 reads the last byte of the instruction again instead of the wrong address
+                    self.bus.load(self.pc.wrapping_sub(1));
+                } else {
+                    // the address before the high byte was fixed
+                    self.bus.load((state.addr & 0xff00).wrapping_sub((state.crossed as u16) << 8) | state.addr & 0x00ff);
+                }
+            }
+            _ => return Err(Error::InvalidOperand { opcode: instruction.opcode, addr: instruction.addr }),
+        }
+        self.tick_state.base = n;
+        Ok(true)
+    }
+
+    fn index(&mut self, base: u16, index: u8) {
+        (self.tick_state.addr, self.tick_state.crossed) = Self::indexed(base, index);
+    }
+
+    /// Reads, writes or modifies the operand, `offset` counts the cycles since the effective address became known.
+    fn access_cycle(&mut self, instruction: Instruction, access: Access, offset: u8) -> Progress {
+        let addr = self.tick_state.addr;
+        match (access, offset) {
+            (Access::Read, 1) => {
+                let value = self.bus.load(addr);
+                self.read_op(instruction, value);
+                if self.decimal() && Metadata::of(self.variant, instruction.code).is_some_and(|metadata| metadata.penalty.decimal) {
+                    return Progress::Next;
+                }
+            }
+            // the extra cycle the 65C02 takes in decimal mode
+            (Access::Read, _) => {
+                self.bus.load(addr);
+            }
+            (Access::Write, _) => self.bus.store(addr, self.store_value(instruction.opcode)),
+            (Access::Modify, 1) => {
+                self.tick_state.value = self.bus.load(addr);
+                return Progress::Next;
+            }
+            (Access::Modify, 2) => {
+                let value = self.tick_state.value;
+                // the NMOS core writes the unmodified value back while it works out the result, the 65C02 reads it again
+                if self.variant.is_cmos() {
+                    self.bus.load(addr);
+                } else {
+                    self.bus.store(addr, value);
+                }
+                self.tick_state.value = self.modify_op(instruction, value);
+                return Progress::Next;
+            }
+            _ => self.bus.store(addr, self.tick_state.value),
+        }
+        Progress::Done(None)
+    }
+
+    /// The cycles of the instructions that have their own bus sequences.
+    fn control_cycle(&mut self, instruction: Instruction, n: u8) -> Result<Progress, Error> {
+        let state = self.tick_state;
+        let stack = 0x0100 | self.sp as u16;
+        match (instruction.opcode, n) {
+            (Opcode::BRK, 2) => {
+                // the signature byte
+                self.load_pc();
+                if let BrkPolicy::Callback(callback) = self.brk_policy {
+                    return Ok(Progress::Done(callback(self).then_some(Stop::Callback)));
+                }
+            }
+            (Opcode::BRK, _) => return Ok(self.push_cycle(n, self.status | 0b00110000, Interrupt::Irq.vector())),
+            (Opcode::JSR, 2) => self.tick_state.value = self.load_pc(),
+            (Opcode::JSR, 3) => {
+                self.bus.load(stack);
+            }
+            (Opcode::JSR, 4) => self.push((self.pc >> 8) as u8),
+            (Opcode::JSR, 5) => self.push(self.pc as u8),
+            (Opcode::JSR, _) => {
+                self.pc = u16::from_le_bytes([state.value, self.bus.load(self.pc)]);
+                return Ok(Progress::Done(None));
+            }
+            (
+                Opcode::RTI | Opcode::RTS | Opcode::PHA | Opcode::PHP | Opcode::PHX | Opcode::PHY | Opcode::PLA | Opcode::PLP | Opcode::PLX | Opcode::PLY,
+                2,
+            ) => {
+                self.bus.load(self.pc);
+            }
+            (Opcode::RTI | Opcode::RTS | Opcode::PLA | Opcode::PLP | Opcode::PLX | Opcode::PLY, 3) => {
+                self.bus.load(stack);
+            }
+            (Opcode::RTI, 4) => self.status = (self.pop() & 0xEF) | 0x20,
+            (Opcode::RTI, 5) | (Opcode::RTS, 4) => self.tick_state.value = self.pop(),
+            (Opcode::RTI, _) => {
+                self.pc = u16::from_le_bytes([state.value, self.pop()]);
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::RTS, 5) => self.pc = u16::from_le_bytes([state.value, self.pop()]),
+            (Opcode::RTS, _) => {
+                self.load_pc();
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::PHA | Opcode::PHP | Opcode::PHX | Opcode::PHY, _) => {
+                let value = match instruction.opcode {
+                    Opcode::PHA => self.accumulator,
+                    Opcode::PHP => self.status | 0b00110000,
+                    Opcode::PHX => self.x,
+                    _ => self.y,
+                };
+                self.push(value);
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::PLA | Opcode::PLP | Opcode::PLX | Opcode::PLY, _) => {
+                let value = self.pop();
+                match instruction.opcode {
+                    Opcode::PLA => self.accumulator = value,
+                    Opcode::PLP => self.status = (value & 0xEF) | 0x20,
+                    Opcode::PLX => self.x = value,
+                    _ => self.y = value,
+                }
+                if instruction.opcode != Opcode::PLP {
+                    self.set_zero_negative(value);
+                }
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::JMP | Opcode::NOP, 2) => self.tick_state.addr = self.load_pc() as u16,
+            (Opcode::JMP | Opcode::NOP, 3) => {
+                self.tick_state.addr |= (self.load_pc() as u16) << 8;
+                if instruction.opcode == Opcode::JMP && matches!(instruction.addr, Address::Absolute(_)) {
+                    self.pc = self.tick_state.addr;
+                    return Ok(Progress::Done(None));
+                }
+            }
+            // the 8 cycle NOP of the 65C02
+            (Opcode::NOP, 4) => {
+                self.bus.load(0xff00 | state.addr & 0x00ff);
+            }
+            (Opcode::NOP, _) => {
+                self.bus.load(0xffff);
+                if n == 8 {
+                    return Ok(Progress::Done(None));
+                }
+            }
+            (Opcode::JMP, 4) if matches!(instruction.addr, Address::Indirect(_)) && !self.variant.is_cmos() => {
+                self.tick_state.value = self.bus.load(state.addr);
+            }
+            (Opcode::JMP, 5) if matches!(instruction.addr, Address::Indirect(_)) && !self.variant.is_cmos() => {
+                // the NMOS core doesn't carry into the high byte of the pointer
+                let high = self.bus.load(state.addr & 0xff00 | (state.addr as u8).wrapping_add(1) as u16);
+                self.pc = u16::from_le_bytes([state.value, high]);
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::JMP, 4) => {
+                // the 65C02 spends a cycle on adding the index or fixing the pointer, it reads the last byte of the instruction again
+                self.bus.load(self.pc.wrapping_sub(1));
+                if let Address::AbsoluteIndirectX(_) = instruction.addr {
+                    self.tick_state.addr = state.addr.wrapping_add(self.x as u16);
+                }
+            }
+            (Opcode::JMP, 5) => self.tick_state.value = self.bus.load(state.addr),
+            (Opcode::JMP, _) => {
+                self.pc = u16::from_le_bytes([state.value, self.bus.load(state.addr.wrapping_add(1))]);
+                return Ok(Progress::Done(None));
+            }
+            (Opcode::WAI | Opcode::STP, 2) => {
+                self.bus.load(self.pc);
+            }
+            (Opcode::WAI, _) => {
+                self.bus.load(self.pc);
+                self.waiting = true;
+                return Ok(Progress::Done(Some(Stop::Wai)));
+            }
+            (Opcode::STP, _) => {
+                self.bus.load(self.pc);
+                self.stopped = true;
+                return Ok(Progress::Done(Some(Stop::Stp)));
+            }
+            (opcode, _) if matches!(instruction.addr, Address::Relative(_)) => {
+                let taken = self.branch_condition(opcode);
+                return Ok(self.branch_cycle(taken, n - 1));
+            }
+            (_, 2) if matches!(instruction.addr, Address::ZeroRelative(..)) => self.tick_state.pointer = self.load_pc(),
+            (_, 3) if matches!(instruction.addr, Address::ZeroRelative(..)) => self.tick_state.value = self.bus.load(state.pointer as u16),
+            (_, 4) if matches!(instruction.addr, Address::ZeroRelative(..)) => {
+                self.bus.load(state.pointer as u16);
+            }
+            (_, _) if matches!(instruction.addr, Address::ZeroRelative(..)) => {
+                let taken = Self::bit_branch_taken(instruction.code, state.value);
+                return Ok(self.branch_cycle(taken, n - 4));
+            }
+            _ => return Err(Error::InvalidOperand { opcode: instruction.opcode, addr: instruction.addr }),
+        }
+        Ok(Progress::Next)
+    }
+
+    /// The cycles of a branch, `offset` is 1 on the cycle that fetches the branch offset.
+    fn branch_cycle(&mut self, taken: bool, offset: u8) -> Progress {
+        match offset {
+            1 => {
+                self.tick_state.value = self.load_pc();
+                if !taken {
+                    return Progress::Done(None);
+                }
+            }
+            2 => {
+                self.bus.load(self.pc);
+                let target = self.pc.wrapping_add(self.tick_state.value as i8 as u16);
+                if target & 0xff00 == self.pc & 0xff00 {
+                    self.pc = target;
+                    return Progress::Done(None);
+                }
+                self.tick_state.addr = target;
+            }
+            _ => {
+                // the address before the high byte was fixed
+                self.bus.load(self.pc & 0xff00 | self.tick_state.addr & 0x00ff);
+                self.pc = self.tick_state.addr;
+                return Progress::Done(None);
+            }
+        }
+        Progress::Next
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::{AccessKind, BrkPolicy, Bus, Clock, Cpu, Error, Fault, Metadata, Opcode, Snapshot, SnapshotError, Stop};
+
+/// A bus that records the accesses made through it while the debugger is stepping, for the watchpoints.
+pub struct Watched<B> {
+    pub bus: B,
+    accesses: Vec<(u16, AccessKind, u8)>,
+    recording: bool,
+}
+
+impl<B> Watched<B> {
+    pub fn new(bus: B) -> Self {
+        Self { bus, accesses: Vec::new(), recording: false }
+    }
+}
+
+impl<B: std::fmt::Debug> std::fmt::Debug for Watched<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.bus.fmt(f)
+    }
+}
+
+impl<B: Bus> Bus for Watched<B> {
+    fn load(&mut self, addr: u16) -> u8 {
+        let value = self.bus.load(addr);
+        if self.recording {
+            self.accesses.push((addr, AccessKind::Read, value));
+        }
+        value
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn take_fault(&mut self) -> Option<Fault> {
+        self.bus.take_fault()
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        if self.recording {
+            self.accesses.push((addr, AccessKind::Write, value));
+        }
+        self.bus.store(addr, value)
+    }
+}
+
+impl<B: Snapshot> Snapshot for Watched<B> {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.bus.save_state(out)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.bus.load_state(data)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// An instruction is about to be executed from the address.
+    Execute,
+}
+
+/// A breakpoint or watchpoint on a range of addresses.
+/// A breakpoint is an execute watchpoint on a single address.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Breakpoint {
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Only accesses made while the condition holds count as hits.
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// How many times the breakpoint was hit, including the ignored hits.
+    pub hits: u64,
+    /// The number of hits to let through before stopping.
+    pub ignore: u64,
+}
+
+impl Breakpoint {
+    fn new(range: RangeInclusive<u16>, read: bool, write: bool, execute: bool) -> Self {
+        Self { range, read, write, execute, condition: None, enabled: true, hits: 0, ignore: 0 }
+    }
+
+    /// Stops before the instruction at `addr` is executed.
+    pub fn pc(addr: u16) -> Self {
+        Self::execute(addr..=addr)
+    }
+
+    /// Stops before an instruction in `range` is executed.
+    pub fn execute(range: RangeInclusive<u16>) -> Self {
+        Self::new(range, false, false, true)
+    }
+
+    /// Stops after an instruction that read from `range`, instruction fetches don't count.
+    pub fn read(range: RangeInclusive<u16>) -> Self {
+        Self::new(range, true, false, false)
+    }
+
+    /// Stops after an instruction that wrote to `range`.
+    pub fn write(range: RangeInclusive<u16>) -> Self {
+        Self::new(range, false, true, false)
+    }
+
+    /// Stops after an instruction that read from or wrote to `range`.
+    pub fn access(range: RangeInclusive<u16>) -> Self {
+        Self::new(range, true, true, false)
+    }
+
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn ignore(mut self, hits: u64) -> Self {
+        self.ignore = hits;
+        self
+    }
+
+    fn watches(&self, addr: u16, kind: WatchKind) -> bool {
+        self.enabled
+            && self.range.contains(&addr)
+            && match kind {
+                WatchKind::Read => self.read,
+                WatchKind::Write => self.write,
+                WatchKind::Execute => self.execute,
+            }
+    }
+}
+
+/// The breakpoint that stopped the debugger.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Hit {
+    pub id: usize,
+    pub addr: u16,
+    pub kind: WatchKind,
+    /// The value read or written, None for execute breakpoints.
+    pub value: Option<u8>,
+}
+
+/// Why the debugger handed control back.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Event {
+    Break(Hit),
+    /// The step finished.
+    Step,
+    /// The cpu stopped, see `Stop`.
+    Stop(Stop),
+    /// The cycle limit ran out.
+    Limit,
+}
+
+/// Runs a cpu one instruction at a time, stopping at breakpoints and watchpoints.
+///
+/// The call depth goes up on JSR, interrupts and BRK when it's taken as an interrupt, and down on RTS and RTI.
+/// Step over and step out rely on it, code that manipulates the return addresses on the stack throws them off.
+#[derive(Debug)]
+pub struct Debugger<B, C> {
+    pub cpu: Cpu<Watched<B>, C>,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_id: usize,
+    depth: i64,
+}
+
+impl<B: Bus, C: Clock> Debugger<B, C> {
+    pub fn new(cpu: Cpu<Watched<B>, C>) -> Self {
+        Self { cpu, breakpoints: BTreeMap::new(), next_id: 0, depth: 0 }
+    }
+
+    /// Adds a breakpoint, returns its id.
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoint(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn breakpoint_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    /// The breakpoints by id, in the order they were added.
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    /// The number of calls entered minus the number of returns.
+    pub fn depth(&self) -> i64 {
+        self.depth
+    }
+
+    /// Runs until a breakpoint is hit, the cpu stops or about `max_cycles` have run.
+    /// Execute breakpoints at the current pc are skipped, so a stopped program can be resumed.
+    pub fn run(&mut self, max_cycles: u64) -> Result<Event, Error> {
+        self.run_while(max_cycles, |_| true)
+    }
+
+    /// Executes a single instruction, along with any interrupt taken before it.
+    pub fn step(&mut self) -> Result<Event, Error> {
+        self.run_while(u64::MAX, |_| false)
+    }
+
+    /// Executes a single instruction, running subroutine calls and interrupts through to their return.
+    pub fn step_over(&mut self, max_cycles: u64) -> Result<Event, Error> {
+        let depth = self.depth;
+        self.run_while(max_cycles, |debugger| debugger.depth > depth)
+    }
+
+    /// Runs until the current subroutine or interrupt handler returns.
+    pub fn step_out(&mut self, max_cycles: u64) -> Result<Event, Error> {
+        let depth = self.depth;
+        self.run_while(max_cycles, |debugger| debugger.depth >= depth)
+    }
+
+    fn run_while(&mut self, max_cycles: u64, more: impl Fn(&Self) -> bool) -> Result<Event, Error> {
+        let limit = self.cpu.cycles.saturating_add(max_cycles);
+        let mut resume = Some(self.cpu.pc);
+        loop {
+            self.cpu.bus.recording = true;
+            let event = self.advance(resume.take());
+            self.cpu.bus.recording = false;
+            if let Some(event) = event? {
+                return Ok(event);
+            }
+            if !more(self) {
+                return Ok(Event::Step);
+            }
+            if self.cpu.cycles >= limit {
+                return Ok(Event::Limit);
+            }
+        }
+    }
+
+    /// Executes an instruction unless an execute breakpoint stops it, `skip` is the pc execute breakpoints are
+    /// ignored at.
+    fn advance(&mut self, skip: Option<u16>) -> Result<Option<Event>, Error> {
+        // taken here rather than by `step`, so the breakpoints see the first instruction of the handler
+        if self.cpu.poll_interrupts().is_some() {
+            self.depth += 1;
+        }
+        let pc = self.cpu.pc;
+        if skip != Some(pc) && !self.cpu.waiting && !self.cpu.stopped {
+            if let Some(hit) = self.check(pc, WatchKind::Execute, None) {
+                return Ok(Some(Event::Break(hit)));
+            }
+        }
+
+        let step = self.cpu.step()?;
+        let accesses = std::mem::take(&mut self.cpu.bus.accesses);
+        if let Some(instruction) = step.instruction {
+            match instruction.opcode {
+                Opcode::JSR => self.depth += 1,
+                Opcode::BRK if self.cpu.brk_policy == BrkPolicy::Interrupt => self.depth += 1,
+                Opcode::RTS | Opcode::RTI => self.depth -= 1,
+                _ => (),
+            }
+        }
+        if let Some(stop) = step.stop {
+            return Ok(Some(Event::Stop(stop)));
+        }
+
+        // the reads that fetched the instruction aren't data accesses
+        let mut fetch = step.pc;
+        let metadata = step.instruction.and_then(|instruction| Metadata::of(self.cpu.variant, instruction.code));
+        let mut len = metadata.map_or(0, |metadata| metadata.bytes);
+        let mut hit = None;
+        for (addr, kind, value) in accesses {
+            let kind = match kind {
+                AccessKind::Read if len > 0 && addr == fetch => {
+                    fetch = fetch.wrapping_add(1);
+                    len -= 1;
+                    continue;
+                }
+                AccessKind::Read => WatchKind::Read,
+                AccessKind::Write => WatchKind::Write,
+            };
+            // every matching access is counted, the first one stops
+            hit = self.check(addr, kind, Some(value)).or(hit);
+        }
+        Ok(hit.map(Event::Break))
+    }
+
+    /// Counts a hit on the breakpoints watching the access, returns the first one that stops.
+    fn check(&mut self, addr: u16, kind: WatchKind, value: Option<u8>) -> Option<Hit> {
+        let mut hit = None;
+        for (id, breakpoint) in &mut self.breakpoints {
+            if !breakpoint.watches(addr, kind) {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.test(&self.cpu) {
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore && hit.is_none() {
+                hit = Some(Hit { id: *id, addr, kind, value });
+            }
+        }
+        hit
+    }
+}
+
+/// An expression over the registers and memory, like `x == 3 && [$0200] != 0`.
+///
+/// The registers are `a`, `x`, `y`, `sp`, `pc`, `p` and `cycles`, and the flags `n`, `v`, `d`, `i`, `z` and `c` read
+/// as 0 or 1. `[addr]` reads a byte of memory without side effects. Numbers are decimal, hexadecimal with `$` or `0x`,
+/// or binary with `0b`. The operators are the ones of C, comparisons and logical operators give 0 or 1.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+/// Where a condition failed to parse.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ConditionError {
+    /// Counted from 1, one past the end when the condition is incomplete.
+    pub column: usize,
+}
+
+impl Display for ConditionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "syntax error in condition at column {}", self.column)
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, end: source.len() };
+        let expr = parser.expr()?;
+        match parser.tokens.first() {
+            Some((offset, _)) => Err(ConditionError { column: offset + 1 }),
+            None => Ok(Self { source: source.trim().to_string(), expr }),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Condition {
+    pub fn eval<B: Bus, C>(&self, cpu: &Cpu<B, C>) -> i64 {
+        self.expr.eval(cpu)
+    }
+
+    /// Whether the condition evaluates to anything but 0.
+    pub fn test<B: Bus, C>(&self, cpu: &Cpu<B, C>) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    Cycles,
+    /// One of the status flags, by its bit.
+    Flag(u8),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Op {
+    LogicalOr,
+    LogicalAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Register(Register),
+    /// A byte of memory.
+    Memory(Box<Expr>),
+    Neg(Box<Expr>),
+    /// Bitwise not, `~`.
+    Not(Box<Expr>),
+    /// Logical not, `!`.
+    LogicalNot(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval<B: Bus, C>(&self, cpu: &Cpu<B, C>) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => match register {
+                Register::A => cpu.accumulator as i64,
+                Register::X => cpu.x as i64,
+                Register::Y => cpu.y as i64,
+                Register::Sp => cpu.sp as i64,
+                Register::Pc => cpu.pc as i64,
+                Register::P => cpu.status as i64,
+                Register::Cycles => cpu.cycles as i64,
+                Register::Flag(bit) => (cpu.status >> bit & 1) as i64,
+            },
+            Expr::Memory(addr) => cpu.bus.peek(addr.eval(cpu) as u16) as i64,
+            Expr::Neg(expr) => expr.eval(cpu).wrapping_neg(),
+            Expr::Not(expr) => !expr.eval(cpu),
+            Expr::LogicalNot(expr) => (expr.eval(cpu) == 0) as i64,
+            Expr::Binary(Op::LogicalOr, left, right) => (left.eval(cpu) != 0 || right.eval(cpu) != 0) as i64,
+            Expr::Binary(Op::LogicalAnd, left, right) => (left.eval(cpu) != 0 && right.eval(cpu) != 0) as i64,
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(cpu), right.eval(cpu));
+                match op {
+                    Op::Eq => (left == right) as i64,
+                    Op::Ne => (left != right) as i64,
+                    Op::Lt => (left < right) as i64,
+                    Op::Le => (left <= right) as i64,
+                    Op::Gt => (left > right) as i64,
+                    Op::Ge => (left >= right) as i64,
+                    Op::Or => left | right,
+                    Op::Xor => left ^ right,
+                    Op::And => left & right,
+                    Op::Shl => u32::try_from(right).ok().and_then(|right| left.checked_shl(right)).unwrap_or(0),
+                    Op::Shr => u32::try_from(right).ok().and_then(|right| left.checked_shr(right)).unwrap_or(0),
+                    Op::Add => left.wrapping_add(right),
+                    Op::Sub => left.wrapping_sub(right),
+                    Op::Mul => left.wrapping_mul(right),
+                    // dividing by 0 gives 0 rather than failing in the middle of a run
+                    Op::Div => left.checked_div(right).unwrap_or(0),
+                    Op::Rem => left.checked_rem(right).unwrap_or(0),
+                    Op::LogicalOr | Op::LogicalAnd => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+enum Token {
+    Number(i64),
+    Register(Register),
+    Punct(&'static str),
+}
+
+/// Longest first, so `<<` isn't read as two `<`.
+const PUNCTUATION: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~", "(", ")",
+    "[", "]",
+];
+
+/// Splits a condition into tokens along with their byte offsets.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ConditionError> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while offset < source.len() {
+        let rest = &source[offset..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            offset += c.len_utf8();
+            continue;
+        }
+        let error = ConditionError { column: offset + 1 };
+        let len = if c.is_ascii_alphanumeric() || c == '$' {
+            let len = rest[1..].find(|c: char| !c.is_ascii_alphanumeric() && c != '_').map_or(rest.len(), |len| len + 1);
+            let word = rest[..len].replace('_', "").to_ascii_lowercase();
+            let number = match word.strip_prefix('$').or_else(|| word.strip_prefix("0x")) {
+                Some(digits) => i64::from_str_radix(digits, 16).ok(),
+                None => match word.strip_prefix("0b") {
+                    Some(digits) => i64::from_str_radix(digits, 2).ok(),
+                    None => word.parse().ok(),
+                },
+            };
+            let token = match (number, word.as_str()) {
+                (Some(number), _) => Token::Number(number),
+                (None, "a") => Token::Register(Register::A),
+                (None, "x") => Token::Register(Register::X),
+                (None, "y") => Token::Register(Register::Y),
+                (None, "sp") => Token::Register(Register::Sp),
+                (None, "pc") => Token::Register(Register::Pc),
+                (None, "p") => Token::Register(Register::P),
+                (None, "cycles") => Token::Register(Register::Cycles),
+                (None, "n") => Token::Register(Register::Flag(7)),
+                (None, "v") => Token::Register(Register::Flag(6)),
+                (None, "d") => Token::Register(Register::Flag(3)),
+                (None, "i") => Token::Register(Register::Flag(2)),
+                (None, "z") => Token::Register(Register::Flag(1)),
+                (None, "c") => Token::Register(Register::Flag(0)),
+                _ => return Err(error),
+            };
+            tokens.push((offset, token));
+            len
+        } else {
+            let punct = PUNCTUATION.iter().find(|punct| rest.starts_with(**punct)).ok_or(error)?;
+            tokens.push((offset, Token::Punct(punct)));
+            punct.len()
+        };
+        offset += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    /// The length of the source, for errors at the end.
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> ConditionError {
+        ConditionError { column: self.tokens.first().map_or(self.end, |(offset, _)| *offset) + 1 }
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = matches!(self.tokens.first(), Some((_, Token::Punct(next))) if *next == punct);
+        if found {
+            self.tokens = &self.tokens[1..];
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ConditionError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ConditionError> {
+        const LEVELS: [&[(&str, Op)]; 10] = [
+            &[("||", Op::LogicalOr)],
+            &[("&&", Op::LogicalAnd)],
+            &[("==", Op::Eq), ("!=", Op::Ne)],
+            &[("<", Op::Lt), ("<=", Op::Le), (">", Op::Gt), (">=", Op::Ge)],
+            &[("|", Op::Or)],
+            &[("^", Op::Xor)],
+            &[("&", Op::And)],
+            &[("<<", Op::Shl), (">>", Op::Shr)],
+            &[("+", Op::Add), ("-", Op::Sub)],
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+        ];
+        self.binary(&LEVELS)
+    }
+
+    fn binary(&mut self, levels: &[&[(&str, Op)]]) -> Result<Expr, ConditionError> {
+        let Some((ops, tighter)) = levels.split_first() else {
+            return self.unary();
+        };
+        let mut left = self.binary(tighter)?;
+        'outer: loop {
+            for (punct, op) in *ops {
+                if self.eat(punct) {
+                    left = Expr::Binary(*op, Box::new(left), Box::new(self.binary(tighter)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Expr::LogicalNot(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.expr()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if self.eat("[") {
+            let expr = self.expr()?;
+            self.expect("]")?;
+            return Ok(Expr::Memory(Box::new(expr)));
+        }
+        let expr = match self.tokens.first() {
+            Some((_, Token::Number(value))) => Expr::Number(*value),
+            Some((_, Token::Register(register))) => Expr::Register(*register),
+            _ => return Err(self.error()),
+        };
+        self.tokens = &self.tokens[1..];
+        Ok(expr)
+    }
+}
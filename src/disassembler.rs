@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+
+use crate::{Address, Bus, Instruction, Metadata, Opcode, Variant};
+
+impl Instruction {
+    /// Decodes the instruction at the start of `bytes`, returns None if the opcode is illegal or the operands are cut off.
+    pub fn decode(variant: Variant, bytes: &[u8]) -> Option<Instruction> {
+        let mut instruction = Instruction::decode_opcode(variant, *bytes.first()?)?;
+        let byte = |i: usize| bytes.get(i).copied();
+        let word = || Some(u16::from_le_bytes([byte(1)?, byte(2)?]));
+        instruction.addr = match instruction.addr {
+            Address::Implied => Address::Implied,
+            Address::Accumulator => Address::Accumulator,
+            Address::Immediate(_) => Address::Immediate(byte(1)?),
+            Address::Zero(_) => Address::Zero(byte(1)?),
+            Address::ZeroX(_) => Address::ZeroX(byte(1)?),
+            Address::ZeroY(_) => Address::ZeroY(byte(1)?),
+            Address::Relative(_) => Address::Relative(byte(1)?),
+            Address::IndirectX(_) => Address::IndirectX(byte(1)?),
+            Address::IndirectY(_) => Address::IndirectY(byte(1)?),
+            Address::ZeroIndirect(_) => Address::ZeroIndirect(byte(1)?),
+            Address::ZeroRelative(..) => Address::ZeroRelative(byte(1)?, byte(2)?),
+            Address::Absolute(_) => Address::Absolute(word()?),
+            Address::AbsoluteX(_) => Address::AbsoluteX(word()?),
+            Address::AbsoluteY(_) => Address::AbsoluteY(word()?),
+            Address::Indirect(_) => Address::Indirect(word()?),
+            Address::AbsoluteIndirectX(_) => Address::AbsoluteIndirectX(word()?),
+        };
+        Some(instruction)
+    }
+
+    /// The address a branch at `pc` jumps to when it's taken.
+    pub fn branch_target(&self, pc: u16) -> Option<u16> {
+        let (len, offset) = match self.addr {
+            Address::Relative(offset) => (2, offset),
+            Address::ZeroRelative(_, offset) => (3, offset),
+            _ => return None,
+        };
+        Some(pc.wrapping_add(len).wrapping_add(offset as i8 as u16))
+    }
+}
+
+/// The mnemonic in lowercase, as the assembler spells it.
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{self:?}").to_lowercase())
+    }
+}
+
+/// The instruction in the syntax of `src/asm/6502.asm`, branch targets are relative to `$` because the address isn't known.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&Disassembler::new(Variant::Nmos).format(*self, None))
+    }
+}
+
+/// One line of a listing, an instruction or a byte that doesn't decode.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// None for data.
+    pub instruction: Option<Instruction>,
+    /// The assembly text.
+    pub text: String,
+    /// The label of `addr`, if it has one.
+    pub label: Option<String>,
+}
+
+/// Prints the line like a listing, with the address and the bytes before the assembly text.
+impl Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(label) = &self.label {
+            writeln!(f, "{label}:")?;
+        }
+        let bytes = self.bytes.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" ");
+        write!(f, "0x{:04x}  {bytes:<8}  {}", self.addr, self.text)
+    }
+}
+
+/// Turns machine code back into assembly that customasm accepts with `src/asm/6502.asm`.
+/// Zero page operands are written with `<`, so they assemble to the same instruction again. The ruledef can't force
+/// absolute addressing, so an absolute operand below 0x100 that would assemble to zero page is written as `#d8` bytes,
+/// with the instruction in a comment.
+/// Bytes that don't decode become `#d8` directives.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Disassembler {
+    pub variant: Variant,
+    /// Names printed instead of the addresses they're for.
+    pub labels: HashMap<u16, String>,
+}
+
+impl Disassembler {
+    pub fn new(variant: Variant) -> Self {
+        Self { variant, labels: HashMap::new() }
+    }
+
+    /// Names `addr`, the name is used wherever the address is an operand.
+    pub fn label(mut self, addr: u16, name: impl Into<String>) -> Self {
+        self.labels.insert(addr, name.into());
+        self
+    }
+
+    /// Disassembles `bytes`, which are loaded at `origin`.
+    pub fn disassemble(&self, bytes: &[u8], origin: u16) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = origin.wrapping_add(offset as u16);
+            let line = self.line(addr, &bytes[offset..]);
+            offset += line.bytes.len();
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Disassembles the instructions starting in `range`, reading the bus with `peek`.
+    pub fn disassemble_bus(&self, bus: &impl Bus, range: std::ops::RangeInclusive<u16>) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut addr = *range.start() as u32;
+        while addr <= *range.end() as u32 {
+            let bytes = [0, 1, 2].map(|i| bus.peek((addr as u16).wrapping_add(i)));
+            let line = self.line(addr as u16, &bytes);
+            addr += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Disassembles the instruction at the start of `bytes`, which can't be empty.
+    pub fn line(&self, addr: u16, bytes: &[u8]) -> Line {
+        let label = self.labels.get(&addr).cloned();
+        let Some(instruction) = Instruction::decode(self.variant, bytes) else {
+            return Line { addr, bytes: bytes[..1].to_vec(), instruction: None, text: format!("#d8 0x{:02x}", bytes[0]), label };
+        };
+        let len = Metadata::of(self.variant, instruction.code).map_or(1, |metadata| metadata.bytes) as usize;
+        Line { addr, bytes: bytes[..len].to_vec(), instruction: Some(instruction), text: self.format(instruction, Some(addr)), label }
+    }
+
+    /// Formats an instruction, `pc` is its address, which resolves branch targets.
+    pub fn format(&self, instruction: Instruction, pc: Option<u16>) -> String {
+        let mut text = instruction.opcode.to_string();
+        let zero = |addr: u8| match self.labels.get(&(addr as u16)) {
+            Some(label) => label.clone(),
+            None => format!("0x{addr:02x}"),
+        };
+        let absolute = |addr: u16| match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("0x{addr:04x}"),
+        };
+        let target = |offset: u8| match pc.and_then(|pc| instruction.branch_target(pc)) {
+            Some(target) => absolute(target),
+            // the offset is from the end of the instruction, `$` is its start
+            None => {
+                let offset = offset as i8 as i16 + if matches!(instruction.addr, Address::ZeroRelative(..)) { 3 } else { 2 };
+                format!("$ {} {}", if offset < 0 { '-' } else { '+' }, offset.abs())
+            }
+        };
+        let operand = match instruction.addr {
+            Address::Implied => String::new(),
+            Address::Accumulator => String::from("a"),
+            Address::Immediate(value) => format!("#0x{value:02x}"),
+            Address::Zero(addr) => format!("<{}", zero(addr)),
+            Address::ZeroX(addr) => format!("<{}, x", zero(addr)),
+            Address::ZeroY(addr) => format!("<{}, y", zero(addr)),
+            Address::Absolute(addr) => absolute(addr),
+            Address::AbsoluteX(addr) => format!("{}, x", absolute(addr)),
+            Address::AbsoluteY(addr) => format!("{}, y", absolute(addr)),
+            Address::Indirect(addr) => format!("({})", absolute(addr)),
+            Address::AbsoluteIndirectX(addr) => format!("({}, x)", absolute(addr)),
+            Address::IndirectX(addr) => format!("({}, x)", zero(addr)),
+            Address::IndirectY(addr) => format!("({}), y", zero(addr)),
+            Address::ZeroIndirect(addr) => format!("({})", zero(addr)),
+            Address::Relative(offset) => target(offset),
+            Address::ZeroRelative(addr, offset) => format!("<{}, {}", zero(addr), target(offset)),
+        };
+        if !operand.is_empty() {
+            write!(text, " {operand}").unwrap();
+        }
+
+        let zero_page = match instruction.addr {
+            Address::Absolute(addr) if addr < 0x100 => Some(Address::Zero(addr as u8)),
+            Address::AbsoluteX(addr) if addr < 0x100 => Some(Address::ZeroX(addr as u8)),
+            Address::AbsoluteY(addr) if addr < 0x100 => Some(Address::ZeroY(addr as u8)),
+            _ => None,
+        };
+        if zero_page.is_some_and(|addr| Instruction::new(self.variant, instruction.opcode, addr).is_some()) {
+            let bytes = instruction.encode().iter().map(|byte| format!("0x{byte:02x}")).collect::<Vec<_>>().join(", ");
+            return format!("#d8 {bytes} ; {text}");
+        }
+        text
+    }
+}
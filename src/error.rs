@@ -0,0 +1,28 @@
+use std::fmt::Display;
+
+use crate::instruction::{Address, Opcode};
+use crate::AccessKind;
+
+/// An error raised while decoding or executing guest code.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Error {
+    /// The byte at `pc` isn't an opcode of the emulated variant.
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// The instruction was given an addressing mode it doesn't support.
+    InvalidOperand { opcode: Opcode, addr: Address },
+    /// The bus refused an access to `addr` made by the instruction at `pc`.
+    BusFault { pc: u16, addr: u16, kind: AccessKind },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IllegalOpcode { pc, opcode } => write!(f, "illegal opcode 0x{opcode:02x} at 0x{pc:04x}"),
+            Error::InvalidOperand { opcode, addr } => write!(f, "{opcode:?} doesn't support the operand {addr:?}"),
+            Error::BusFault { pc, addr, kind: AccessKind::Read } => write!(f, "bus fault reading 0x{addr:04x} at 0x{pc:04x}"),
+            Error::BusFault { pc, addr, kind: AccessKind::Write } => write!(f, "bus fault writing 0x{addr:04x} at 0x{pc:04x}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
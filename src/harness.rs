@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+
+use crate::{Bus, Clock, Cpu, Disassembler, Error, Snapshot, SnapshotError, Step, Stop};
+
+/// 64KiB of RAM covering the whole address space, the memory the standard test binaries expect.
+#[derive(Clone, PartialEq, Eq)]
+pub struct FlatBus(pub Box<[u8; 0x10000]>);
+
+impl FlatBus {
+    pub fn new() -> Self {
+        Self(Box::new([0; 0x10000]))
+    }
+
+    /// Creates a bus with `image` loaded at `addr`, anything past the end of the address space is dropped.
+    pub fn from_image(image: &[u8], addr: u16) -> Self {
+        let mut bus = Self::new();
+        let len = image.len().min(0x10000 - addr as usize);
+        bus.0[addr as usize..addr as usize + len].copy_from_slice(&image[..len]);
+        bus
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for FlatBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FlatBus")
+    }
+}
+
+impl Bus for FlatBus {
+    fn load(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        self.0[addr as usize] = value;
+    }
+}
+
+impl Snapshot for FlatBus {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.0.save_state(out)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.0.load_state(data)
+    }
+}
+
+/// Where and why `run_to_trap` ended.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Trap {
+    /// The address of the instruction the program got stuck on.
+    pub pc: u16,
+    pub reason: TrapReason,
+    /// The last instructions executed, oldest first.
+    pub trace: Vec<Step>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TrapReason {
+    /// An instruction jumped or branched to itself.
+    Loop,
+    /// The cpu stopped before getting stuck.
+    Stop(Stop),
+    /// The guest code faulted.
+    Error(Error),
+    /// The cycle limit ran out.
+    Limit,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            TrapReason::Loop => writeln!(f, "trapped at 0x{:04x}", self.pc)?,
+            TrapReason::Stop(stop) => writeln!(f, "stopped at 0x{:04x} ({stop:?})", self.pc)?,
+            TrapReason::Error(error) => writeln!(f, "{error}")?,
+            TrapReason::Limit => writeln!(f, "ran out of cycles at 0x{:04x}", self.pc)?,
+        }
+        for step in &self.trace {
+            if let Some(interrupt) = step.interrupt {
+                writeln!(f, "  {interrupt:?}")?;
+            }
+            match step.instruction {
+                Some(instruction) => writeln!(f, "  0x{:04x}  {}", step.pc, Disassembler::default().format(instruction, Some(step.pc)))?,
+                None => writeln!(f, "  0x{:04x}  -", step.pc)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: Bus, C: Clock> Cpu<B, C> {
+    /// Runs until an instruction jumps or branches to itself, which is how test programs like Klaus Dormann's signal
+    /// success or failure, until the cpu stops or after `max_cycles`.
+    /// `hook` is called before every instruction, to drive the interrupt lines for example.
+    /// The last `trace_len` instructions are kept for the report.
+    pub fn run_to_trap(&mut self, max_cycles: u64, trace_len: usize, mut hook: impl FnMut(&mut Self)) -> Trap {
+        let mut trace = VecDeque::with_capacity(trace_len);
+        let limit = self.cycles.saturating_add(max_cycles);
+        let reason = loop {
+            if self.cycles >= limit {
+                break TrapReason::Limit;
+            }
+            hook(self);
+            let step = match self.step() {
+                Ok(step) => step,
+                Err(error) => break TrapReason::Error(error),
+            };
+            if trace_len > 0 {
+                if trace.len() == trace_len {
+                    trace.pop_front();
+                }
+                trace.push_back(step);
+            }
+            if let Some(stop) = step.stop {
+                break TrapReason::Stop(stop);
+            }
+            if step.instruction.is_some() && self.pc == step.pc {
+                break TrapReason::Loop;
+            }
+        };
+        Trap { pc: self.pc, reason, trace: trace.into() }
+    }
+}
@@ -1,10 +1,55 @@
-#[derive(Debug, Clone, Copy)]
+use crate::Variant;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct Instruction {
     pub opcode: Opcode,
     pub addr: Address,
+    /// The byte the instruction was decoded from.
+    pub code: u8,
 }
 
 include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
+include!(concat!(env!("OUT_DIR"), "/metadata.rs"));
+
+/// What opcodes.txt says about an opcode.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Metadata {
+    pub opcode: Opcode,
+    /// The length of the instruction, including the opcode.
+    pub bytes: u8,
+    /// The cycles taken before any penalties.
+    pub cycles: u8,
+    pub penalty: Penalty,
+    /// The status flags the instruction can change, as a mask of the status register.
+    pub flags: u8,
+}
+
+/// When an instruction takes more than its base cycles.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Penalty {
+    /// One more cycle when indexing crosses a page.
+    pub page: bool,
+    /// One more cycle when the branch is taken, and another when it lands on a different page.
+    pub branch: bool,
+    /// One more cycle in decimal mode, the 65C02 spends it on fixing the flags of ADC and SBC.
+    pub decimal: bool,
+}
+
+impl Metadata {
+    /// Looks up an opcode, returns None if the variant doesn't decode it.
+    pub fn of(variant: Variant, code: u8) -> Option<&'static Metadata> {
+        let table = if variant.is_cmos() { &CMOS } else { &NMOS };
+        table[code as usize].as_ref()
+    }
+
+    /// The cycles taken, `crossed` is whether indexing or a taken branch crossed a page.
+    pub fn cycles(&self, crossed: bool, taken: bool, decimal: bool) -> u8 {
+        let page = (self.penalty.page && crossed) as u8;
+        let branch = if self.penalty.branch && taken { 1 + crossed as u8 } else { 0 };
+        let decimal = (self.penalty.decimal && decimal) as u8;
+        self.cycles + page + branch + decimal
+    }
+}
 
 /// The addressing mode and operands.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -17,9 +62,108 @@ pub enum Address {
     ZeroX(u8),
     ZeroY(u8),
     Relative(u8),
+    /// A zero page address followed by a branch offset, used by BBR and BBS.
+    ZeroRelative(u8, u8),
     Accumulator,
     Indirect(u16),
+    /// `(abs,x)`, only used by JMP on the 65C02.
+    AbsoluteIndirectX(u16),
     IndirectX(u8),
     IndirectY(u8),
+    /// `(zp)`, 65C02 only.
+    ZeroIndirect(u8),
     Immediate(u8),
 }
+
+/// How an instruction uses its operand, which decides the bus accesses it makes.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum Access {
+    /// Reads a value into the registers, like LDA.
+    Read,
+    /// Stores a register, like STA.
+    Write,
+    /// Reads a value and writes back the result, like INC. The accumulator addressing mode belongs here too.
+    Modify,
+    /// Only works on registers, like TAX.
+    Implied,
+    /// Jumps, branches, stack operations and the instructions that stop the cpu, each has its own bus sequence.
+    Control,
+}
+
+impl Instruction {
+    pub(crate) fn access(&self) -> Access {
+        match self.opcode {
+            Opcode::NOP if self.addr == Address::Implied => Access::Implied,
+            // the 8 cycle NOP of the 65C02 doesn't read its operand
+            Opcode::NOP if self.code == 0x5C && matches!(self.addr, Address::Absolute(_)) => Access::Control,
+            Opcode::ORA
+            | Opcode::AND
+            | Opcode::EOR
+            | Opcode::ADC
+            | Opcode::SBC
+            | Opcode::CMP
+            | Opcode::CPX
+            | Opcode::CPY
+            | Opcode::BIT
+            | Opcode::LDA
+            | Opcode::LDX
+            | Opcode::LDY
+            | Opcode::LAX
+            | Opcode::ANC
+            | Opcode::ALR
+            | Opcode::ARR
+            | Opcode::SBX
+            | Opcode::LAS
+            | Opcode::NOP => Access::Read,
+            Opcode::STA | Opcode::STX | Opcode::STY | Opcode::SAX | Opcode::STZ => Access::Write,
+            Opcode::ASL
+            | Opcode::LSR
+            | Opcode::ROL
+            | Opcode::ROR
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::SLO
+            | Opcode::RLA
+            | Opcode::SRE
+            | Opcode::RRA
+            | Opcode::DCP
+            | Opcode::ISC
+            | Opcode::TSB
+            | Opcode::TRB
+            | Opcode::RMB0
+            | Opcode::RMB1
+            | Opcode::RMB2
+            | Opcode::RMB3
+            | Opcode::RMB4
+            | Opcode::RMB5
+            | Opcode::RMB6
+            | Opcode::RMB7
+            | Opcode::SMB0
+            | Opcode::SMB1
+            | Opcode::SMB2
+            | Opcode::SMB3
+            | Opcode::SMB4
+            | Opcode::SMB5
+            | Opcode::SMB6
+            | Opcode::SMB7 => Access::Modify,
+            Opcode::CLC
+            | Opcode::SEC
+            | Opcode::CLI
+            | Opcode::SEI
+            | Opcode::CLD
+            | Opcode::SED
+            | Opcode::CLV
+            | Opcode::TAX
+            | Opcode::TAY
+            | Opcode::TXA
+            | Opcode::TYA
+            | Opcode::TSX
+            | Opcode::TXS
+            | Opcode::INX
+            | Opcode::INY
+            | Opcode::DEX
+            | Opcode::DEY => Access::Implied,
+            _ => Access::Control,
+        }
+    }
+}
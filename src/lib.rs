@@ -1,10 +1,26 @@
-use std::time::Instant;
-
-use instruction::{Address, Instruction, Opcode};
+pub use analysis::{Analysis, Block, Function};
+pub use assembler::{AsmError, AsmErrorKind, Assembler, Assembly};
+pub use debugger::{Breakpoint, Condition, ConditionError, Debugger, Event, Hit, WatchKind, Watched};
+pub use disassembler::{Disassembler, Line};
+pub use error::Error;
+pub use harness::{FlatBus, Trap, TrapReason};
+use instruction::Access;
+pub use instruction::{Address, Instruction, Metadata, Opcode, Penalty};
+pub use memory::{AccessKind, Device, Fault, MemoryMap, Unmapped, UnmappedAccess};
+pub use snapshot::{Snapshot, SnapshotError};
+pub use throttle::Throttle;
 
+mod analysis;
+mod assembler;
+mod cycle;
+mod debugger;
+mod disassembler;
+mod error;
+mod harness;
 mod instruction;
-
-//TODO: Reduce code duplication
+mod memory;
+mod snapshot;
+mod throttle;
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct Cpu<B, C> {
@@ -19,6 +35,187 @@ pub struct Cpu<B, C> {
     pub accumulator: u8,
 
     pub clock: C,
+
+    pub variant: Variant,
+
+    /// The level of the IRQ line, true while a device is requesting an interrupt.
+    pub irq_line: bool,
+    /// The level of the NMI line.
+    pub nmi_line: bool,
+    /// Set when the NMI line becomes active and cleared once the NMI has been serviced.
+    pub nmi_pending: bool,
+    /// The number of cycles executed so far.
+    pub cycles: u64,
+    /// How many cycles the last call to `run_for_cycles` ran past its budget.
+    pub overshoot: u64,
+    /// Set by WAI on the 65C02, the cpu doesn't execute anything until an interrupt line becomes active.
+    pub waiting: bool,
+    /// Set by STP on the 65C02, the cpu doesn't execute anything until it's reset.
+    pub stopped: bool,
+
+    pub brk_policy: BrkPolicy<B, C>,
+    pub fault_policy: FaultPolicy<B, C>,
+
+    /// The instruction `tick` is in the middle of.
+    tick_state: cycle::Cycle,
+}
+
+/// The flavour of 6502 being emulated.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Variant {
+    /// The original NMOS 6502.
+    #[default]
+    Nmos,
+    /// The NMOS core used in the NES, which has decimal mode disconnected.
+    Ricoh2A03,
+    /// The WDC W65C02S, which adds new instructions and fixes the bugs of the NMOS core.
+    Wdc65C02,
+}
+
+impl Variant {
+    /// Whether setting the decimal flag makes ADC and SBC use BCD arithmetic.
+    pub fn has_decimal(self) -> bool {
+        self != Variant::Ricoh2A03
+    }
+
+    /// Whether the undocumented opcodes of the NMOS core are decoded.
+    pub fn has_undocumented(self) -> bool {
+        !self.is_cmos()
+    }
+
+    /// Whether this is a 65C02, which adds instructions and fixes the quirks of the NMOS core.
+    pub fn is_cmos(self) -> bool {
+        self == Variant::Wdc65C02
+    }
+}
+
+/// What the cpu does when it executes BRK.
+pub enum BrkPolicy<B, C> {
+    /// Stop the emulation, `pc` is left pointing at the BRK. Takes the 1 cycle of the opcode fetch.
+    Halt,
+    /// Push the return address and status and jump through $FFFE like the real chip does.
+    Interrupt,
+    /// Skip the signature byte and call the function instead of jumping through $FFFE.
+    /// The function returns whether the emulation should stop. Takes 2 cycles, for the opcode and the signature byte.
+    Callback(fn(&mut Cpu<B, C>) -> bool),
+}
+
+// These are implemented by hand because deriving them would require `B` and `C` to implement them as well.
+impl<B, C> Clone for BrkPolicy<B, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B, C> Copy for BrkPolicy<B, C> {}
+
+impl<B, C> PartialEq for BrkPolicy<B, C> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (BrkPolicy::Halt, BrkPolicy::Halt) | (BrkPolicy::Interrupt, BrkPolicy::Interrupt) => true,
+            (BrkPolicy::Callback(a), BrkPolicy::Callback(b)) => *a as usize == *b as usize,
+            _ => false,
+        }
+    }
+}
+
+impl<B, C> Eq for BrkPolicy<B, C> {}
+
+impl<B, C> std::fmt::Debug for BrkPolicy<B, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrkPolicy::Halt => f.write_str("Halt"),
+            BrkPolicy::Interrupt => f.write_str("Interrupt"),
+            BrkPolicy::Callback(callback) => f.debug_tuple("Callback").field(&(*callback as usize as *const ())).finish(),
+        }
+    }
+}
+
+/// What the cpu does when the bus refuses an access, like a write to protected memory.
+/// The bus is checked once the instruction making the access has finished.
+pub enum FaultPolicy<B, C> {
+    /// Fail with `Error::BusFault`.
+    Error,
+    /// Call the function, which returns whether to fail with `Error::BusFault` or carry on.
+    Callback(fn(&mut Cpu<B, C>, Fault) -> bool),
+}
+
+impl<B, C> Clone for FaultPolicy<B, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B, C> Copy for FaultPolicy<B, C> {}
+
+impl<B, C> PartialEq for FaultPolicy<B, C> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FaultPolicy::Error, FaultPolicy::Error) => true,
+            (FaultPolicy::Callback(a), FaultPolicy::Callback(b)) => *a as usize == *b as usize,
+            _ => false,
+        }
+    }
+}
+
+impl<B, C> Eq for FaultPolicy<B, C> {}
+
+impl<B, C> std::fmt::Debug for FaultPolicy<B, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultPolicy::Error => f.write_str("Error"),
+            FaultPolicy::Callback(callback) => f.debug_tuple("Callback").field(&(*callback as usize as *const ())).finish(),
+        }
+    }
+}
+
+/// Why the emulation stopped.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Stop {
+    /// A BRK was executed with `BrkPolicy::Halt`.
+    Brk,
+    /// The BRK callback asked to stop.
+    Callback,
+    /// One of the undocumented opcodes that lock up the NMOS core was executed, `pc` is left pointing at it.
+    Jam,
+    /// The 65C02 is waiting for an interrupt after WAI.
+    Wai,
+    /// The 65C02 was stopped by STP and has to be reset.
+    Stp,
+}
+
+/// What happened during a call to `Cpu::step`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Step {
+    /// The address the instruction was fetched from, after any interrupt was taken.
+    pub pc: u16,
+    /// The instruction that was executed, None if the cpu is waiting or stopped.
+    pub instruction: Option<Instruction>,
+    /// The number of cycles spent, including page crossing and branch penalties and the interrupt sequence.
+    pub cycles: u8,
+    /// The interrupt that was taken before the instruction.
+    pub interrupt: Option<Interrupt>,
+    /// Why the emulation should stop, if it should.
+    pub stop: Option<Stop>,
+}
+
+/// The hardware interrupts, in order of priority.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Interrupt {
+    Reset,
+    Nmi,
+    Irq,
+}
+
+impl Interrupt {
+    /// The address the handler's address is loaded from.
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::Nmi => 0xFFFA,
+            Interrupt::Reset => 0xFFFC,
+            Interrupt::Irq => 0xFFFE,
+        }
+    }
 }
 
 include!(concat!(env!("OUT_DIR"), "/parsing.rs"));
@@ -33,7 +230,18 @@ impl<B: Bus, C> Cpu<B, C> {
             sp: 0,
             clock,
             pc: 0x0200,
-            bus
+            bus,
+            variant: Variant::Nmos,
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            cycles: 0,
+            overshoot: 0,
+            waiting: false,
+            stopped: false,
+            brk_policy: BrkPolicy::Halt,
+            fault_policy: FaultPolicy::Error,
+            tick_state: cycle::Cycle::default(),
         }
     }
 
@@ -47,7 +255,17 @@ impl<B: Bus, C> Cpu<B, C> {
             y,
             status,
             accumulator,
-
+            variant: Variant::Nmos,
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            cycles: 0,
+            overshoot: 0,
+            waiting: false,
+            stopped: false,
+            brk_policy: BrkPolicy::Halt,
+            fault_policy: FaultPolicy::Error,
+            tick_state: cycle::Cycle::default(),
         };
         this.set_reserved(true);
         this
@@ -93,572 +311,783 @@ impl<B: Bus, C> Cpu<B, C> {
         u16::from_le_bytes([self.pop(), self.pop()])
     }
 
-    /// This is a helper method for ALU operations.
-    /// Returns a value, ncycles and optionally an address.
-    /// If the address is None, the accumulator should be used.
-    fn alu_operands(&self, addr: Address) -> (u8, u8) {
-        match addr {
-            Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-            Address::Absolute(addr) => (self.bus.load(addr), 4),
-            Address::AbsoluteX(addr) => {
-                let final_addr = addr.wrapping_add(self.x as u16);
-                let value = self.bus.load(final_addr);
-                let ncycles = if addr & 0xff00 == final_addr & 0xff00 {
-                    // same memory page
-                    4
-                } else {
-                    // different memory page
-                    5
-                };
-                (value, ncycles)
-            }
-            Address::AbsoluteY(addr) => {
-                let final_addr = addr.wrapping_add(self.y as u16);
-                let value = self.bus.load(final_addr);
-                let ncycles = if addr & 0xff00 == final_addr & 0xff00 {
-                    // same memory page
-                    4
-                } else {
-                    // different memory page
-                    5
-                };
-                (value, ncycles)
-            }
-            Address::ZeroX(addr) => (self.bus.load(addr.wrapping_add(self.x) as u16), 4),
-            Address::IndirectX(indirect) => {
-                let addr = self
-                    .bus
-                    .load_u16_zp(indirect.wrapping_add(self.x));
-                (self.bus.load(addr), 6)
-            }
-            Address::IndirectY(indirect) => {
-                // load the address stored in zero page
-                let addr = self.bus.load_u16_zp(indirect);
-                // add the y register to it.
-                let final_addr = addr.wrapping_add(self.y as u16);
-                let value = self.bus.load(final_addr);
-                let ncycles = if addr & 0xff00 == final_addr & 0xff00 {
-                    // same memory page
-                    5
-                } else {
-                    // different memory page
-                    6
-                };
-                (value, ncycles)
-            }
-            Address::Immediate(value) => (value, 2),
-            _ => unreachable!(),
+    /// Pushes the return address and the status register, then jumps through `vector`.
+    /// This is the sequence shared by BRK and the hardware interrupts.
+    fn interrupt(&mut self, return_addr: u16, status: u8, vector: u16) {
+        self.push_u16(return_addr);
+        self.push(status);
+        self.set_interrupt_disable(true);
+        if self.variant.is_cmos() {
+            self.set_decimal(false);
         }
+        self.pc = self.bus.load_u16(vector);
     }
 
-    /// This is a helper method for shift operations.
-    /// Returns a value, ncycles and optionally an address.
-    /// If the address is None, the accumulator should be used.
-    fn shift_operands(&self, addr: Address) -> (u8, u8, Option<u16>) {
-        match addr {
-            Address::Accumulator => (self.accumulator, 2, None),
-            Address::Zero(addr) => (self.bus.load(addr as u16), 5, Some(addr as u16)),
-            Address::ZeroX(addr) => {
-                let addr = addr.wrapping_add(self.x) as u16;
-                (self.bus.load(addr), 6, Some(addr))
-            }
-            Address::Absolute(addr) => (self.bus.load(addr), 6, Some(addr)),
-            Address::AbsoluteX(addr) => {
-                let addr = addr.wrapping_add(self.x as u16);
-                (self.bus.load(addr), 7, Some(addr))
+    /// This is a helper method for ALU operations.
+    /// Returns a value and whether indexing crossed a page, or None if the addressing mode isn't supported.
+    fn alu_operands(&mut self, addr: Address) -> Option<(u8, bool)> {
+        Some(match addr {
+            Address::Immediate(value) => (value, false),
+            addr => {
+                let (addr, crossed) = self.modify_operands(addr)?;
+                (self.bus.load(addr), crossed)
             }
-            _ => unreachable!(),
-        }
+        })
+    }
+
+    /// This is a helper method for store operations.
+    /// Returns the address to store to.
+    fn store_operands(&mut self, addr: Address) -> Option<u16> {
+        Some(self.modify_operands(addr)?.0)
+    }
+
+    /// This is a helper method for instructions that access memory.
+    /// Returns the effective address and whether indexing crossed a page, or None if the addressing mode isn't supported.
+    fn modify_operands(&mut self, addr: Address) -> Option<(u16, bool)> {
+        Some(match addr {
+            Address::Zero(addr) => (addr as u16, false),
+            Address::ZeroX(addr) => (addr.wrapping_add(self.x) as u16, false),
+            Address::ZeroY(addr) => (addr.wrapping_add(self.y) as u16, false),
+            Address::Absolute(addr) => (addr, false),
+            Address::AbsoluteX(addr) => Self::indexed(addr, self.x),
+            Address::AbsoluteY(addr) => Self::indexed(addr, self.y),
+            Address::IndirectX(indirect) => (self.bus.load_u16_zp(indirect.wrapping_add(self.x)), false),
+            // the address stored in zero page, plus the y register
+            Address::IndirectY(indirect) => Self::indexed(self.bus.load_u16_zp(indirect), self.y),
+            Address::ZeroIndirect(indirect) => (self.bus.load_u16_zp(indirect), false),
+            _ => return None,
+        })
+    }
+
+    /// Adds an index register to an address, returns the address and whether it's on a different memory page.
+    fn indexed(addr: u16, index: u8) -> (u16, bool) {
+        let final_addr = addr.wrapping_add(index as u16);
+        (final_addr, addr & 0xff00 != final_addr & 0xff00)
+    }
+
+    /// Looks up the metadata of an instruction, `pc` is the address it was fetched from.
+    fn metadata(&self, instruction: Instruction, pc: u16) -> Result<&'static Metadata, Error> {
+        Metadata::of(self.variant, instruction.code).ok_or(Error::IllegalOpcode { pc, opcode: instruction.code })
     }
 }
 
 impl<B: Bus, C: Clock> Cpu<B, C> {
-    pub fn run(&mut self) {
+    /// Runs until the emulation is stopped or the guest code faults.
+    pub fn run(&mut self) -> Result<Stop, Error> {
         loop {
-            let instruction = self.fetch();
-            if self.execute(instruction) {
-                break;
+            if let Some(stop) = self.step()?.stop {
+                return Ok(stop);
             }
         }
     }
-    /// Executes an instruction, the bool indicates if the instruction was BRK.
-    pub fn execute(&mut self, instruction: Instruction) -> bool {
-        let start = Instant::now();
-        let ncycles = match instruction.opcode {
-            Opcode::BRK => {
-                // Push the program counter + 2 onto the stack.
-                self.push_u16(self.pc.wrapping_add(1)); // this is 1 because we already incremented by 1 while fetching
 
-                // Set the break flag to true and push the status register onto the stack.
-                self.push(self.status | 0b00010000);
-                self.set_interrupt_disable(true);
-                self.pc = self.bus.load_u16(0xFFFE);
-                self.clock.cycles(7, start);
-                return true;
-            }
-            Opcode::PHP => {
-                self.push(self.status | 0b00110000);
-                3
-            }
-            Opcode::BPL => self.branch(!self.negative(), instruction.addr),
-            Opcode::CLC => {
-                self.set_carry(false);
-                2
+    /// Runs for `n` cycles or until the emulation is stopped, returns None if the cycles ran out.
+    /// An instruction can't be cut short, so the last one usually runs past the budget.
+    /// That overshoot is taken off the budget of the next call, so consecutive calls add up to exactly the cycles requested.
+    /// A cpu waiting for an interrupt idles until the end of the budget.
+    pub fn run_for_cycles(&mut self, n: u64) -> Result<Option<Stop>, Error> {
+        if self.overshoot >= n {
+            self.overshoot -= n;
+            return Ok(None);
+        }
+        let target = self.cycles + n - self.overshoot;
+        self.overshoot = 0;
+        while self.cycles < target {
+            match self.step()?.stop {
+                None => (),
+                Some(Stop::Wai) => {
+                    self.cycles = target;
+                    self.clock.sync(self.cycles);
+                    return Ok(Some(Stop::Wai));
+                }
+                Some(stop) => return Ok(Some(stop)),
             }
-            Opcode::ORA => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.accumulator |= value;
-                self.set_zero(self.accumulator == 0);
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                ncycles
+        }
+        self.overshoot = self.cycles - target;
+        Ok(None)
+    }
+
+    /// Runs until `predicate` returns true or the emulation is stopped, returns None if the predicate was met.
+    /// The predicate is checked before every instruction.
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&Self) -> bool) -> Result<Option<Stop>, Error> {
+        while !predicate(self) {
+            if let Some(stop) = self.step()?.stop {
+                return Ok(Some(stop));
             }
-            Opcode::ASL => {
-                let (value, ncycles, addr) = self.shift_operands(instruction.addr);
-                self.set_carry(value & 0x80 == 0x80);
+        }
+        Ok(None)
+    }
 
-                let result = value << 1;
-                if let Some(addr) = addr {
-                    self.bus.store(addr, result)
-                } else {
-                    self.accumulator = result;
+    /// Services pending interrupts, then fetches and executes a single instruction.
+    pub fn step(&mut self) -> Result<Step, Error> {
+        let start = self.cycles;
+        let interrupt = self.poll_interrupts();
+        let pc = self.pc;
+        let stop = if self.stopped {
+            Some(Stop::Stp)
+        } else if self.waiting {
+            Some(Stop::Wai)
+        } else {
+            None
+        };
+        if stop.is_some() {
+            return Ok(Step { pc, instruction: None, cycles: (self.cycles - start) as u8, interrupt, stop });
+        }
+
+        let instruction = self.fetch()?;
+        let stop = self.execute(instruction)?;
+        Ok(Step { pc, instruction: Some(instruction), cycles: (self.cycles - start) as u8, interrupt, stop })
+    }
+
+    /// Executes an instruction, returns why the emulation should stop if it should.
+    /// pc is expected past the instruction, where `fetch` leaves it. Accesses the bus refused are reported like in `step`.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<Option<Stop>, Error> {
+        // Fetching moved pc past the operands, or just past the opcode if the variant doesn't have it.
+        let bytes = Metadata::of(self.variant, instruction.code).map_or(1, |metadata| metadata.bytes);
+        let pc = self.pc.wrapping_sub(bytes as u16);
+        let (ncycles, stop) = self.dispatch(instruction, pc)?;
+        self.cycles += ncycles as u64;
+        self.clock.sync(self.cycles);
+        self.check_fault(pc)?;
+        Ok(stop)
+    }
+
+    /// Takes the fault the bus reported, if any, and handles it according to the fault policy.
+    /// `pc` is the address of the instruction that made the access.
+    fn check_fault(&mut self, pc: u16) -> Result<(), Error> {
+        let Some(fault) = self.bus.take_fault() else {
+            return Ok(());
+        };
+        let fail = match self.fault_policy {
+            FaultPolicy::Error => true,
+            FaultPolicy::Callback(callback) => callback(self, fault),
+        };
+        if fail {
+            return Err(Error::BusFault { pc, addr: fault.addr, kind: fault.kind });
+        }
+        Ok(())
+    }
+
+    /// Executes an instruction, returns ncycles and why the emulation should stop if it should.
+    /// `pc` is the address of the instruction.
+    fn dispatch(&mut self, instruction: Instruction, pc: u16) -> Result<(u8, Option<Stop>), Error> {
+        let invalid = Error::InvalidOperand { opcode: instruction.opcode, addr: instruction.addr };
+        let metadata = self.metadata(instruction, pc)?;
+        // the penalties are worked out along the way, the cycles come from the table
+        let mut crossed = false;
+        let mut taken = false;
+        let mut stop = None;
+        match instruction.opcode {
+            Opcode::BRK => match self.brk_policy {
+                BrkPolicy::Halt => {
+                    // Point back at the BRK, it was already fetched. The fetch is the only cycle spent.
+                    self.pc = self.pc.wrapping_sub(1);
+                    return Ok((1, Some(Stop::Brk)));
+                }
+                BrkPolicy::Interrupt => {
+                    // Push the program counter + 2 onto the stack, it's 1 because we already incremented by 1 while fetching.
+                    // The status register is pushed with the break flag set.
+                    self.interrupt(self.pc.wrapping_add(1), self.status | 0b00010000, Interrupt::Irq.vector());
                 }
-                self.set_zero(result == 0);
-                self.set_negative(result & 0x80 == 0x80);
-                ncycles
+                BrkPolicy::Callback(callback) => {
+                    // Skip the signature byte, fetching the opcode and the signature byte are the only cycles spent.
+                    self.pc = self.pc.wrapping_add(1);
+                    return Ok((2, callback(self).then_some(Stop::Callback)));
+                }
+            },
+            Opcode::PHP => {
+                self.push(self.status | 0b00110000);
+            }
+            Opcode::BPL | Opcode::BMI | Opcode::BVC | Opcode::BVS | Opcode::BCC | Opcode::BCS | Opcode::BNE | Opcode::BEQ | Opcode::BRA => {
+                taken = self.branch_condition(instruction.opcode);
+                crossed = self.branch(taken, instruction.addr).ok_or(invalid)?;
             }
             Opcode::JSR => {
                 let addr = if let Address::Absolute(addr) = instruction.addr {
                     addr
                 } else {
-                    unreachable!()
+                    return Err(invalid);
                 };
                 // push the last byte of the instruction to the stack
-                self.push_u16(self.pc - 1);
+                self.push_u16(self.pc.wrapping_sub(1));
 
                 self.pc = addr;
-                6
-            }
-            Opcode::BIT => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-                    Address::Absolute(addr) => (self.bus.load(addr), 4),
-                    _ => unreachable!(),
-                };
-                self.set_negative(value & 0x80 == 0x80);
-                self.set_overflow(value & 0x40 == 0x40);
-                let result = value & self.accumulator;
-                self.set_zero(result == 0);
-                ncycles
             }
             Opcode::PLP => {
                 self.status = (self.pop() & 0xEF) | 0x20;
-                4
-            },
-            Opcode::BMI => self.branch(self.negative(), instruction.addr),
-            Opcode::SEC => {
-                self.set_carry(true);
-                2
-            },
-            Opcode::AND => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.accumulator &= value;
-                self.set_zero(self.accumulator == 0);
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                ncycles
-            },
-            Opcode::ROL => {
-                let (value, ncycles, addr): (u8, u8, Option<u16>) = self.shift_operands(instruction.addr);
-                let old_carry = self.carry();
-                self.set_carry(value & 0x80 == 0x80);
-                let result = value << 1 | old_carry as u8;
-                self.set_negative(result & 0x80 == 0x80);
-                self.set_zero(result == 0);
-                if let Some(addr) = addr {
-                    self.bus.store(addr, result)
-                } else {
-                    self.accumulator = result
-                }
-                ncycles
             },
             Opcode::RTI => {
                 self.status = (self.pop() & 0xEF) | 0x20;
                 self.pc = self.pop_u16();
-                6
             },
             Opcode::PHA => {
                 self.push(self.accumulator);
-                3
             },
             Opcode::JMP => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Absolute(value) => (value, 3),
+                self.pc = match instruction.addr {
+                    Address::Absolute(value) => value,
+                    // the 65C02 fixed the bug where the address wraps around within its page
+                    Address::Indirect(addr) if self.variant.is_cmos() => self.bus.load_u16(addr),
                     Address::Indirect(addr) => {
                         let ls = self.bus.load(addr);
                         let ms = self.bus.load((addr as u8).wrapping_add(1) as u16 | (addr & 0xff00));
-                        (u16::from_le_bytes([ls, ms]), 5)
+                        u16::from_le_bytes([ls, ms])
                     },
-                    _ => unreachable!()
+                    Address::AbsoluteIndirectX(addr) => self.bus.load_u16(addr.wrapping_add(self.x as u16)),
+                    _ => return Err(invalid),
                 };
-                self.pc = value;
-                ncycles
-            },
-            Opcode::BVC => self.branch(!self.overflow(), instruction.addr),
-            Opcode::CLI => {
-                self.set_interrupt_disable(false);
-                2
-            },
-            Opcode::EOR => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.accumulator ^= value;
-                self.set_zero(self.accumulator == 0);
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                ncycles
-            },
-            Opcode::LSR => {
-                let (value, ncycles, addr) = self.shift_operands(instruction.addr);
-                self.set_carry(value & 1 == 1);
-
-                let result = value >> 1;
-                if let Some(addr) = addr {
-                    self.bus.store(addr, result)
-                } else {
-                    self.accumulator = result;
-                }
-                self.set_zero(result == 0);
-                self.set_negative(result & 0x80 == 0x80);
-                ncycles
             },
             Opcode::RTS => {
-                self.pc = self.pop_u16()+1;
-                6
+                self.pc = self.pop_u16().wrapping_add(1);
             },
             Opcode::PLA => {
                 self.accumulator = self.pop();
                 self.set_zero(self.accumulator == 0);
                 self.set_negative(self.accumulator & 0x80 == 0x80);
-                4
-            },
-            Opcode::BVS => self.branch(self.overflow(), instruction.addr),
-            Opcode::SEI => {
-                self.set_interrupt_disable(true);
-                2
-            },
-            Opcode::ADC => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.adc(value);
-                ncycles
-            },
-            Opcode::ROR => {
-                let (value, ncycles, addr): (u8, u8, Option<u16>) = self.shift_operands(instruction.addr);
-                let old_carry = self.carry();
-                self.set_carry(value & 1 == 1);
-                let result = value >> 1 | ((old_carry as u8) << 7);
-                self.set_negative(result & 0x80 == 0x80);
-                self.set_zero(result == 0);
-                if let Some(addr) = addr {
-                    self.bus.store(addr, result)
-                } else {
-                    self.accumulator = result
-                }
-                ncycles
-            },
-            Opcode::STY => {
-                let (addr, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (addr as u16, 3),
-                    Address::ZeroX(addr) => (addr.wrapping_add(self.x) as u16, 4),
-                    Address::Absolute(addr) => (addr, 4),
-                    _ => unreachable!()
-                };
-                self.bus.store(addr, self.y);
-                ncycles
-            },
-            Opcode::DEY => {
-                self.y = self.y.wrapping_sub(1);
-                self.set_zero(self.y == 0);
-                self.set_negative(self.y & 0x80 == 0x80);
-                2
-            },
-            Opcode::BCC => self.branch(!self.carry(), instruction.addr),
-            Opcode::TYA => {
-                self.accumulator = self.y;
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                self.set_zero(self.accumulator == 0);
-                2
-            },
-            Opcode::STA => {
-                let (addr, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (addr as u16, 3),
-                    Address::Absolute(addr) => (addr, 4),
-                    Address::AbsoluteX(addr) => {
-                        (addr.wrapping_add(self.x as u16), 5)
-                    }
-                    Address::AbsoluteY(addr) => {
-                        (addr.wrapping_add(self.y as u16), 5)
-                    }
-                    Address::ZeroX(addr) => (addr.wrapping_add(self.x) as u16, 4),
-                    Address::IndirectX(indirect) => {
-                        let addr = self
-                            .bus
-                            .load_u16_zp(indirect.wrapping_add(self.x));
-                        (addr, 6)
-                    }
-                    Address::IndirectY(indirect) => {
-                        // load the address stored in zero page
-                        let addr = self.bus.load_u16_zp(indirect);
-                        // add the y register to it.
-                        (addr.wrapping_add(self.y as u16), 6)
-                    }
-                    _ => unreachable!(),
-                };
-                self.bus.store(addr, self.accumulator);
-                ncycles
-                
-            },
-            Opcode::STX => {
-                let (addr, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (addr as u16, 3),
-                    Address::ZeroY(addr) => (addr.wrapping_add(self.y) as u16, 4),
-                    Address::Absolute(addr) => (addr, 4),
-                    _ => unreachable!()
-                };
-                self.bus.store(addr, self.x);
-                ncycles
-            },
-            Opcode::TXA => {
-                self.accumulator = self.x;
-                self.set_zero(self.accumulator == 0);
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                2
-            },
-            Opcode::TXS => {
-                self.sp = self.x;
-                2
-            },
-            Opcode::LDY => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-                    Address::ZeroX(addr) => (self.bus.load(addr.wrapping_add(self.x) as u16), 4),
-                    Address::Absolute(addr) => (self.bus.load(addr), 4),
-                    Address::AbsoluteX(addr) => {
-                        let final_addr = addr.wrapping_add(self.x as u16);
-                        let ncycles = if final_addr & 0xff00 == addr & 0xff00 {
-                            4
-                        } else {
-                            5
-                        };
-                        (self.bus.load(final_addr), ncycles)
-                    },
-                    Address::Immediate(value) => (value, 2),
-                    _ => unreachable!()
-                };
-                self.y = value;
-                self.set_zero(self.y == 0);
-                self.set_negative(self.y & 0x80 == 0x80);
-                ncycles
-            },
-            Opcode::TAY => {
-                self.y = self.accumulator;
-                self.set_zero(self.y == 0);
-                self.set_negative(self.y & 0x80 == 0x80);
-                2
             },
-            Opcode::BCS => self.branch(self.carry(), instruction.addr),
-            Opcode::CLV => {
-                self.set_overflow(false);
-                2
-            },
-            Opcode::LDA => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.accumulator = value;
-                self.set_zero(self.accumulator == 0);
-                self.set_negative(self.accumulator & 0x80 == 0x80);
-                ncycles
+            Opcode::NOP => match instruction.addr {
+                Address::Implied => (),
+                Address::Absolute(_) if instruction.code == 0x5C => (),
+                // the undocumented NOPs read their operand
+                addr => crossed = self.alu_operands(addr).ok_or(invalid)?.1,
             },
-            Opcode::LDX => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-                    Address::ZeroY(addr) => (self.bus.load(addr.wrapping_add(self.y) as u16), 4),
-                    Address::Absolute(addr) => (self.bus.load(addr), 4),
-                    Address::AbsoluteY(addr) => {
-                        let final_addr = addr.wrapping_add(self.y as u16);
-                        let ncycles = if final_addr & 0xff00 == addr & 0xff00 {
-                            4
-                        } else {
-                            5
-                        };
-                        (self.bus.load(final_addr), ncycles)
-                    },
-                    Address::Immediate(value) => (value, 2),
-                    _ => unreachable!()
-                };
-                self.x = value;
-                self.set_zero(self.x == 0);
-                self.set_negative(self.x & 0x80 == 0x80);
-                ncycles
+            Opcode::JAM => {
+                self.pc = self.pc.wrapping_sub(1);
+                return Ok((0, Some(Stop::Jam)));
             },
-            Opcode::CPY => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Immediate(value) => (value, 2),
-                    Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-                    Address::Absolute(addr) => (self.bus.load(addr), 4),
-                    _ => unreachable!() 
-                };
-                self.set_carry(self.y >= value);
-                let temp = self.y.wrapping_sub(value);
-                self.set_zero(temp == 0);
-                self.set_negative(temp & 0x80 == 0x80);
-                ncycles
+            Opcode::PHX => {
+                self.push(self.x);
             },
-            Opcode::TAX => {
-                self.x = self.accumulator;
-                self.set_zero(self.x == 0);
-                self.set_negative(self.x & 0x80 == 0x80);
-                2
+            Opcode::PHY => {
+                self.push(self.y);
             },
-            Opcode::TSX => {
-                self.x = self.sp;
+            Opcode::PLX => {
+                self.x = self.pop();
                 self.set_zero(self.x == 0);
                 self.set_negative(self.x & 0x80 == 0x80);
-                2
             },
-            Opcode::INY => {
-                self.y = self.y.wrapping_add(1);
+            Opcode::PLY => {
+                self.y = self.pop();
                 self.set_zero(self.y == 0);
                 self.set_negative(self.y & 0x80 == 0x80);
-                2
-            },
-            Opcode::BNE => self.branch(!self.zero(), instruction.addr),
-            Opcode::CLD => {
-                self.set_decimal(false); // decimal mode is not supported even though I implemented this instruction;
-                2
-            },
-            Opcode::CMP => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.set_carry(self.accumulator >= value);
-                let temp = self.accumulator.wrapping_sub(value);
-                self.set_zero(temp == 0);
-                self.set_negative(temp & 0x80 == 0x80);
-                ncycles
-                // 0b 0110 0101
-                // 0b 1110 0100
-
-            },
-            Opcode::DEC => {
-                let (addr, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (addr as u16, 5),
-                    Address::ZeroX(addr) => (addr.wrapping_add(self.x) as u16, 6),
-                    Address::Absolute(addr) => (addr, 6),
-                    Address::AbsoluteX(addr) => (addr.wrapping_add(self.x as u16), 7),
-                    _ => unreachable!()
-                };
-                let value = self.bus.load(addr).wrapping_sub(1);
-                self.set_zero(value == 0);
-                self.set_negative(value & 0x80 == 0x80); 
-                self.bus.store(addr, value);
-                ncycles
-            },
-            Opcode::DEX => {
-                self.x = self.x.wrapping_sub(1);
-                self.set_zero(self.x == 0);
-                self.set_negative(self.x & 0x80 == 0x80);
-                2
             },
-            Opcode::CPX => {
-                let (value, ncycles) = match instruction.addr {
-                    Address::Immediate(value) => (value, 2),
-                    Address::Zero(addr) => (self.bus.load(addr as u16), 3),
-                    Address::Absolute(addr) => (self.bus.load(addr), 4),
-                    _ => unreachable!() 
+            Opcode::BBR0 | Opcode::BBR1 | Opcode::BBR2 | Opcode::BBR3 | Opcode::BBR4 | Opcode::BBR5 | Opcode::BBR6 | Opcode::BBR7
+            | Opcode::BBS0 | Opcode::BBS1 | Opcode::BBS2 | Opcode::BBS3 | Opcode::BBS4 | Opcode::BBS5 | Opcode::BBS6 | Opcode::BBS7 => {
+                let (addr, offset) = if let Address::ZeroRelative(addr, offset) = instruction.addr {
+                    (addr, offset)
+                } else {
+                    return Err(invalid);
                 };
-                self.set_carry(self.x >= value);
-                let temp = self.x.wrapping_sub(value);
-                self.set_zero(temp == 0);
-                self.set_negative(temp & 0x80 == 0x80);
-                ncycles
-            },
-            Opcode::INX => {
-                self.x = self.x.wrapping_add(1);
-                self.set_zero(self.x == 0);
-                self.set_negative(self.x & 0x80 == 0x80);
-                2
+                let value = self.bus.load(addr as u16);
+                taken = Self::bit_branch_taken(instruction.code, value);
+                crossed = self.branch(taken, Address::Relative(offset)).ok_or(invalid)?;
             },
-            Opcode::BEQ => {self.branch(self.zero(), instruction.addr)},
-            Opcode::SED => {
-                eprintln!("decimal mode set but it's not supported!");
-                self.set_decimal(true); // even though this instruction is implemented, decimal mode is not supported
-                2
+            Opcode::WAI => {
+                self.waiting = true;
+                stop = Some(Stop::Wai);
             },
-            Opcode::SBC => {
-                let (value, ncycles) = self.alu_operands(instruction.addr);
-                self.adc(!value);
-                ncycles
-            },
-            Opcode::INC => {
-                let (addr, ncycles) = match instruction.addr {
-                    Address::Zero(addr) => (addr as u16, 5),
-                    Address::ZeroX(addr) => (addr.wrapping_add(self.x) as u16, 6),
-                    Address::Absolute(addr) => (addr, 6),
-                    Address::AbsoluteX(addr) => (addr.wrapping_add(self.x as u16), 7),
-                    _ => unreachable!()
-                };
-                let value = self.bus.load(addr).wrapping_add(1);
-                self.set_zero(value == 0);
-                self.set_negative(value & 0x80 == 0x80);
-                self.bus.store(addr, value);
-                ncycles
+            Opcode::STP => {
+                self.stopped = true;
+                stop = Some(Stop::Stp);
             },
-            Opcode::NOP => 2,
-        };
-        self.clock.cycles(ncycles, start);
-        false
-    }
-
-    fn branch(&mut self, flag: bool, address: Address) -> u8 {
-        if flag {
-            if let Address::Relative(address) = address {
-                let most_significant = self.pc.to_le_bytes()[1]; //
-                self.pc = (self.pc as i16).wrapping_add(address as i8 as i16) as u16;
-                if self.pc.to_le_bytes()[1] == most_significant {
-                    // branch on same memory page
-                    3
-                } else {
-                    // branch on a different memory page
-                    4
+            opcode => match instruction.access() {
+                Access::Read => {
+                    let value;
+                    (value, crossed) = self.alu_operands(instruction.addr).ok_or(invalid)?;
+                    self.read_op(instruction, value);
                 }
-            } else {
-                panic!("illegal addressing mode")
-            }
-        } else {
-            2
+                Access::Write => {
+                    let addr = self.store_operands(instruction.addr).ok_or(invalid)?;
+                    self.bus.store(addr, self.store_value(opcode));
+                }
+                Access::Modify if instruction.addr == Address::Accumulator => {
+                    self.accumulator = self.modify_op(instruction, self.accumulator);
+                }
+                Access::Modify => {
+                    let addr;
+                    (addr, crossed) = self.modify_operands(instruction.addr).ok_or(invalid)?;
+                    let value = self.bus.load(addr);
+                    let value = self.modify_op(instruction, value);
+                    self.bus.store(addr, value);
+                }
+                Access::Implied => self.implied_op(opcode),
+                Access::Control => return Err(invalid),
+            },
         }
+        Ok((metadata.cycles(crossed, taken, self.decimal()), stop))
     }
 
-    fn adc(&mut self, value: u8) {
-        let mut temp = self.accumulator as u16;
-        temp += value as u16 + self.carry() as u16; // can't overflow
-        self.set_overflow((self.accumulator & value & 0x80 != temp as u8 & 0x80) && self.accumulator & 0x80 == value & 0x80);
-        self.accumulator = temp as u8;
-        
-        self.set_carry((temp & 0x0100) == 0x0100);
-        self.set_zero(self.accumulator == 0);
-        self.set_negative(self.accumulator & 0x80 == 0x80);
+    /// Sets the level of the IRQ line.
+    /// The interrupt is taken before the next instruction for as long as the line is active and interrupts are enabled.
+    pub fn set_irq(&mut self, active: bool) {
+        self.irq_line = active;
     }
-}
 
-// In order to avoid writing the same code 8 times, I defined a macro that does it for me.
-macro_rules! flag {
-    ($name:ident, $set_name:ident, $bit:literal) => {
-        pub fn $name(&self) -> bool {
-            // This gets the nth bit of a byte and returns it as a boolean
-            ((self.status & (1 << $bit)) >> $bit) != 0
+    /// Sets the level of the NMI line.
+    /// NMI is edge triggered, so only a transition from inactive to active requests an interrupt.
+    pub fn set_nmi(&mut self, active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
         }
-        pub fn $set_name(&mut self, bit: bool) {
-            self.status = (self.status & !(1 << $bit)) | (bit as u8) << $bit
+        self.nmi_line = active;
+    }
+
+    /// Services a pending NMI or an active IRQ, this is done by `run` before every instruction.
+    /// An active line also ends a WAI, even when the IRQ is masked.
+    pub fn poll_interrupts(&mut self) -> Option<Interrupt> {
+        if self.stopped {
+            return None;
         }
-    };
-}
+        if self.nmi_pending || self.irq_line {
+            self.waiting = false;
+        }
+        if self.nmi_pending {
+            self.nmi();
+            Some(Interrupt::Nmi)
+        } else if self.irq_line && self.irq() {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        }
+    }
 
-impl<B, C> Cpu<B, C> {
+    /// Takes an IRQ right away, returns false if it was masked by the interrupt disable flag.
+    pub fn irq(&mut self) -> bool {
+        if self.interrupt_disable() {
+            return false;
+        }
+        self.interrupt(self.pc, (self.status & !0b00010000) | 0b00100000, Interrupt::Irq.vector());
+        self.cycles += 7;
+        self.clock.sync(self.cycles);
+        true
+    }
+
+    /// Takes an NMI right away, NMIs can't be masked.
+    pub fn nmi(&mut self) {
+        self.nmi_pending = false;
+        self.interrupt(self.pc, (self.status & !0b00010000) | 0b00100000, Interrupt::Nmi.vector());
+        self.cycles += 7;
+        self.clock.sync(self.cycles);
+    }
+
+    /// Resets the cpu and jumps to the address in the reset vector.
+    /// Like the real chip, the stack pointer is decremented by 3 without anything being written to the stack.
+    pub fn reset(&mut self) {
+        self.nmi_pending = false;
+        self.waiting = false;
+        self.stopped = false;
+        self.sp = self.sp.wrapping_sub(3);
+        self.set_interrupt_disable(true);
+        if self.variant.is_cmos() {
+            self.set_decimal(false);
+        }
+        self.pc = self.bus.load_u16(Interrupt::Reset.vector());
+        self.cycles += 7;
+        self.clock.sync(self.cycles);
+    }
+
+    /// Returns whether the branch landed on a different memory page, or None if the addressing mode isn't relative.
+    fn branch(&mut self, flag: bool, address: Address) -> Option<bool> {
+        let Address::Relative(address) = address else {
+            return None;
+        };
+        if !flag {
+            return Some(false);
+        }
+        let most_significant = self.pc.to_le_bytes()[1];
+        self.pc = (self.pc as i16).wrapping_add(address as i8 as i16) as u16;
+        Some(self.pc.to_le_bytes()[1] != most_significant)
+    }
+
+    /// Returns whether a relative branch is taken.
+    fn branch_condition(&self, opcode: Opcode) -> bool {
+        match opcode {
+            Opcode::BPL => !self.negative(),
+            Opcode::BMI => self.negative(),
+            Opcode::BVC => !self.overflow(),
+            Opcode::BVS => self.overflow(),
+            Opcode::BCC => !self.carry(),
+            Opcode::BCS => self.carry(),
+            Opcode::BNE => !self.zero(),
+            Opcode::BEQ => self.zero(),
+            // BRA
+            _ => true,
+        }
+    }
+
+    /// Returns whether BBR or BBS branches for the zero page value it read.
+    fn bit_branch_taken(code: u8, value: u8) -> bool {
+        // the bit number is encoded in the opcode and BBS is BBR with the high bit of the opcode set
+        let bit = code >> 4 & 0x07;
+        let set = code & 0x80 == 0x80;
+        (value >> bit & 1 == 1) == set
+    }
+
+    fn set_zero_negative(&mut self, value: u8) {
+        self.set_zero(value == 0);
+        self.set_negative(value & 0x80 == 0x80);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_carry(register >= value);
+        self.set_zero_negative(register.wrapping_sub(value));
+    }
+
+    /// Applies an instruction that reads its operand to the registers.
+    fn read_op(&mut self, instruction: Instruction, value: u8) {
+        match instruction.opcode {
+            Opcode::ORA => {
+                self.accumulator |= value;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::AND => {
+                self.accumulator &= value;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::EOR => {
+                self.accumulator ^= value;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::ADC => self.adc(value),
+            Opcode::SBC => self.sbc(value),
+            Opcode::CMP => self.compare(self.accumulator, value),
+            Opcode::CPX => self.compare(self.x, value),
+            Opcode::CPY => self.compare(self.y, value),
+            Opcode::BIT => {
+                // BIT immediate only affects the zero flag
+                if !matches!(instruction.addr, Address::Immediate(_)) {
+                    self.set_negative(value & 0x80 == 0x80);
+                    self.set_overflow(value & 0x40 == 0x40);
+                }
+                self.set_zero(value & self.accumulator == 0);
+            }
+            Opcode::LDA => {
+                self.accumulator = value;
+                self.set_zero_negative(value);
+            }
+            Opcode::LDX => {
+                self.x = value;
+                self.set_zero_negative(value);
+            }
+            Opcode::LDY => {
+                self.y = value;
+                self.set_zero_negative(value);
+            }
+            Opcode::LAX => {
+                self.accumulator = value;
+                self.x = value;
+                self.set_zero_negative(value);
+            }
+            Opcode::ANC => {
+                self.accumulator &= value;
+                self.set_zero_negative(self.accumulator);
+                self.set_carry(self.negative());
+            }
+            Opcode::ALR => {
+                let value = self.accumulator & value;
+                self.set_carry(value & 1 == 1);
+                self.accumulator = value >> 1;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::ARR => self.arr(value),
+            Opcode::SBX => {
+                let temp = self.accumulator & self.x;
+                self.set_carry(temp >= value);
+                self.x = temp.wrapping_sub(value);
+                self.set_zero_negative(self.x);
+            }
+            Opcode::LAS => {
+                let value = value & self.sp;
+                self.accumulator = value;
+                self.x = value;
+                self.sp = value;
+                self.set_zero_negative(value);
+            }
+            // the NOPs read their operand and throw it away
+            _ => (),
+        }
+    }
+
+    /// Applies a read-modify-write instruction to a value, returns the value to write back.
+    fn modify_op(&mut self, instruction: Instruction, value: u8) -> u8 {
+        // the bit number of RMB and SMB is encoded in the opcode
+        let bit = instruction.code >> 4 & 0x07;
+        match instruction.opcode {
+            Opcode::ASL => self.asl(value),
+            Opcode::LSR => self.lsr(value),
+            Opcode::ROL => self.rol(value),
+            Opcode::ROR => self.ror(value),
+            Opcode::INC => {
+                let value = value.wrapping_add(1);
+                self.set_zero_negative(value);
+                value
+            }
+            Opcode::DEC => {
+                let value = value.wrapping_sub(1);
+                self.set_zero_negative(value);
+                value
+            }
+            Opcode::SLO => {
+                let value = self.asl(value);
+                self.accumulator |= value;
+                self.set_zero_negative(self.accumulator);
+                value
+            }
+            Opcode::RLA => {
+                let value = self.rol(value);
+                self.accumulator &= value;
+                self.set_zero_negative(self.accumulator);
+                value
+            }
+            Opcode::SRE => {
+                let value = self.lsr(value);
+                self.accumulator ^= value;
+                self.set_zero_negative(self.accumulator);
+                value
+            }
+            Opcode::RRA => {
+                let value = self.ror(value);
+                self.adc(value);
+                value
+            }
+            Opcode::DCP => {
+                let value = value.wrapping_sub(1);
+                self.compare(self.accumulator, value);
+                value
+            }
+            Opcode::ISC => {
+                let value = value.wrapping_add(1);
+                self.sbc(value);
+                value
+            }
+            Opcode::TSB => {
+                self.set_zero(value & self.accumulator == 0);
+                value | self.accumulator
+            }
+            Opcode::TRB => {
+                self.set_zero(value & self.accumulator == 0);
+                value & !self.accumulator
+            }
+            Opcode::RMB0 | Opcode::RMB1 | Opcode::RMB2 | Opcode::RMB3 | Opcode::RMB4 | Opcode::RMB5 | Opcode::RMB6 | Opcode::RMB7 => {
+                value & !(1 << bit)
+            }
+            // SMB
+            _ => value | 1 << bit,
+        }
+    }
+
+    fn asl(&mut self, value: u8) -> u8 {
+        self.set_carry(value & 0x80 == 0x80);
+        let result = value << 1;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn lsr(&mut self, value: u8) -> u8 {
+        self.set_carry(value & 1 == 1);
+        let result = value >> 1;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn rol(&mut self, value: u8) -> u8 {
+        let old_carry = self.carry();
+        self.set_carry(value & 0x80 == 0x80);
+        let result = value << 1 | old_carry as u8;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn ror(&mut self, value: u8) -> u8 {
+        let old_carry = self.carry();
+        self.set_carry(value & 1 == 1);
+        let result = value >> 1 | (old_carry as u8) << 7;
+        self.set_zero_negative(result);
+        result
+    }
+
+    /// Returns the value a store instruction writes.
+    fn store_value(&self, opcode: Opcode) -> u8 {
+        match opcode {
+            Opcode::STA => self.accumulator,
+            Opcode::STX => self.x,
+            Opcode::STY => self.y,
+            Opcode::SAX => self.accumulator & self.x,
+            // STZ
+            _ => 0,
+        }
+    }
+
+    /// Executes an instruction that only works on registers.
+    fn implied_op(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::CLC => self.set_carry(false),
+            Opcode::SEC => self.set_carry(true),
+            Opcode::CLI => self.set_interrupt_disable(false),
+            Opcode::SEI => self.set_interrupt_disable(true),
+            Opcode::CLD => self.set_decimal(false),
+            Opcode::SED => self.set_decimal(true),
+            Opcode::CLV => self.set_overflow(false),
+            Opcode::TAX => {
+                self.x = self.accumulator;
+                self.set_zero_negative(self.x);
+            }
+            Opcode::TAY => {
+                self.y = self.accumulator;
+                self.set_zero_negative(self.y);
+            }
+            Opcode::TXA => {
+                self.accumulator = self.x;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::TYA => {
+                self.accumulator = self.y;
+                self.set_zero_negative(self.accumulator);
+            }
+            Opcode::TSX => {
+                self.x = self.sp;
+                self.set_zero_negative(self.x);
+            }
+            Opcode::TXS => self.sp = self.x,
+            Opcode::INX => {
+                self.x = self.x.wrapping_add(1);
+                self.set_zero_negative(self.x);
+            }
+            Opcode::INY => {
+                self.y = self.y.wrapping_add(1);
+                self.set_zero_negative(self.y);
+            }
+            Opcode::DEX => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_zero_negative(self.x);
+            }
+            Opcode::DEY => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_zero_negative(self.y);
+            }
+            // NOP
+            _ => (),
+        }
+    }
+
+    fn adc(&mut self, value: u8) {
+        if self.decimal() && self.variant.has_decimal() {
+            self.adc_decimal(value);
+        } else {
+            self.adc_binary(value);
+        }
+    }
+
+    fn adc_binary(&mut self, value: u8) {
+        let mut temp = self.accumulator as u16;
+        temp += value as u16 + self.carry() as u16; // can't overflow
+        self.set_overflow((self.accumulator & value & 0x80 != temp as u8 & 0x80) && self.accumulator & 0x80 == value & 0x80);
+        self.accumulator = temp as u8;
+        
+        self.set_carry((temp & 0x0100) == 0x0100);
+        self.set_zero(self.accumulator == 0);
+        self.set_negative(self.accumulator & 0x80 == 0x80);
+    }
+
+    /// BCD addition the way the NMOS 6502 does it.
+    /// N and V are taken from the result before the high nibble is adjusted and Z from the binary sum,
+    /// so only the accumulator and the carry are valid BCD results.
+    /// The 65C02 sets N and Z from the final result.
+    fn adc_decimal(&mut self, value: u8) {
+        let carry = self.carry() as u16;
+        let binary = (self.accumulator as u16 + value as u16 + carry) as u8;
+
+        let mut low = (self.accumulator & 0x0f) as u16 + (value & 0x0f) as u16 + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut result = (self.accumulator & 0xf0) as u16 + (value & 0xf0) as u16 + low;
+
+        self.set_negative(result & 0x80 == 0x80);
+        self.set_overflow(!(self.accumulator ^ value) & (self.accumulator ^ result as u8) & 0x80 == 0x80);
+        self.set_zero(binary == 0);
+
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+        self.set_carry(result >= 0x0100);
+        self.accumulator = result as u8;
+        if self.variant.is_cmos() {
+            self.set_zero(self.accumulator == 0);
+            self.set_negative(self.accumulator & 0x80 == 0x80);
+        }
+    }
+
+    /// AND followed by ROR, with C and V taken from bits 6 and 5 of the result.
+    /// The NMOS core also applies a decimal adjustment to the result while the decimal flag is set.
+    fn arr(&mut self, value: u8) {
+        let temp = self.accumulator & value;
+        let mut result = temp >> 1 | (self.carry() as u8) << 7;
+        self.set_negative(result & 0x80 == 0x80);
+        self.set_zero(result == 0);
+        if self.decimal() && self.variant.has_decimal() {
+            self.set_overflow((temp ^ result) & 0x40 == 0x40);
+            if (temp & 0x0f) + (temp & 0x01) > 0x05 {
+                result = (result & 0xf0) | (result.wrapping_add(0x06) & 0x0f);
+            }
+            let carry = (temp & 0xf0) as u16 + (temp & 0x10) as u16 > 0x50;
+            if carry {
+                result = result.wrapping_add(0x60);
+            }
+            self.set_carry(carry);
+        } else {
+            self.set_carry(result & 0x40 == 0x40);
+            self.set_overflow(((result >> 6) ^ (result >> 5)) & 1 == 1);
+        }
+        self.accumulator = result;
+    }
+
+    fn sbc(&mut self, value: u8) {
+        let accumulator = self.accumulator;
+        let borrow = !self.carry() as i16;
+        // The NMOS 6502 sets every flag as if the subtraction was binary, even in decimal mode.
+        self.adc_binary(!value);
+        if self.decimal() && self.variant.is_cmos() {
+            // The 65C02 adjusts the binary result instead and sets N and Z from it.
+            let low = (accumulator & 0x0f) as i16 - (value & 0x0f) as i16 - borrow;
+            let mut result = accumulator as i16 - value as i16 - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if low < 0 {
+                result -= 0x06;
+            }
+            self.accumulator = result as u8;
+            self.set_zero(self.accumulator == 0);
+            self.set_negative(self.accumulator & 0x80 == 0x80);
+        } else if self.decimal() && self.variant.has_decimal() {
+            let mut low = (accumulator & 0x0f) as i16 - (value & 0x0f) as i16 - borrow;
+            if low < 0 {
+                low = ((low - 0x06) & 0x0f) - 0x10;
+            }
+            let mut result = (accumulator & 0xf0) as i16 - (value & 0xf0) as i16 + low;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.accumulator = result as u8;
+        }
+    }
+}
+
+// In order to avoid writing the same code 8 times, I defined a macro that does it for me.
+macro_rules! flag {
+    ($name:ident, $set_name:ident, $bit:literal) => {
+        pub fn $name(&self) -> bool {
+            // This gets the nth bit of a byte and returns it as a boolean
+            ((self.status & (1 << $bit)) >> $bit) != 0
+        }
+        pub fn $set_name(&mut self, bit: bool) {
+            self.status = (self.status & !(1 << $bit)) | (bit as u8) << $bit
+        }
+    };
+}
+
+impl<B, C> Cpu<B, C> {
     flag!(negative, set_negative, 7);
     flag!(overflow, set_overflow, 6);
     flag!(reserved, set_reserved, 5);
@@ -670,13 +1099,26 @@ impl<B, C> Cpu<B, C> {
 }
 
 pub trait Bus {
-    fn load(&self, addr: u16) -> u8;
-    fn load_u16(&self, addr: u16) -> u16 {
+    /// A read by the cpu, devices can react to it, like a status register that clears when it's read.
+    fn load(&mut self, addr: u16) -> u8;
+    fn load_u16(&mut self, addr: u16) -> u16 {
         u16::from_le_bytes([self.load(addr), self.load(addr.wrapping_add(1))])
     }
-    fn load_u16_zp(&self, addr: u8) -> u16 {
+    fn load_u16_zp(&mut self, addr: u8) -> u16 {
         u16::from_le_bytes([self.load(addr as u16), self.load(addr.wrapping_add(1) as u16)])
     }
+    /// Reads without side effects, for debuggers and other tools looking at memory.
+    /// By default nothing can be seen and everything reads as 0.
+    fn peek(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn peek_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))])
+    }
+    /// Returns an access the bus refused since the last call, the cpu checks after every instruction.
+    fn take_fault(&mut self) -> Option<Fault> {
+        None
+    }
     fn store(&mut self, addr: u16, value: u8);
     fn store_u16(&mut self, addr: u16, value: u16) {
         let bytes = value.to_le_bytes();
@@ -685,39 +1127,1136 @@ pub trait Bus {
     }
 }
 
+/// Paces the emulation, see `Throttle`.
 pub trait Clock {
-    /// Waits for n amount of cycles.
-    fn cycles(&mut self, n: u8, start: Instant);
+    /// Called with the total number of cycles executed after every instruction, or every cycle when using `tick`.
+    /// This is on the hot path, so implementations should only look at the time once in a while.
+    fn sync(&mut self, cycles: u64);
+}
+
+/// Runs as fast as possible.
+impl Clock for () {
+    fn sync(&mut self, _: u64) {}
 }
 
 
 #[cfg(test)]
 mod test {
-    use std::time::Instant;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
 
     use serde_derive::Deserialize;
     use serde_derive::Serialize;
 
-    const URL: &str = "https://raw.githubusercontent.com/TomHarte/ProcessorTests/main/nes6502/v1/";
+    use super::Variant;
+
+    const URL: &str = "https://raw.githubusercontent.com/TomHarte/ProcessorTests/main/";
+
+    /// The suite is picked with the `M6502_SUITE` environment variable.
+    /// `nes6502` (the default) has decimal mode disabled, `6502` exercises it and `wdc65c02` tests the 65C02.
+    fn suite() -> (String, Variant) {
+        let suite = std::env::var("M6502_SUITE").unwrap_or_else(|_| String::from("nes6502"));
+        let variant = match suite.as_str() {
+            "nes6502" => Variant::Ricoh2A03,
+            "6502" => Variant::Nmos,
+            "wdc65c02" => Variant::Wdc65C02,
+            _ => panic!("unknown test suite {suite}"),
+        };
+        (suite, variant)
+    }
 
     #[test]
+    #[ignore = "needs the ProcessorTests vectors, from the directory in M6502_TESTS or downloaded"]
     fn test() {
+        let (suite, variant) = suite();
         let opcodes = include_str!("../opcodes.txt");
-        for opcode in opcodes.lines().map(|v| {
+        // The opcodes that stop the cpu aren't tested.
+        let opcodes: Vec<String> = opcodes.lines().filter(|v| {
+            let line: Vec<&str> = v.split_whitespace().collect();
+            let decoded = match line.get(6) {
+                None => true,
+                Some(&"undocumented") => variant.has_undocumented(),
+                Some(&"cmos") => variant.is_cmos(),
+                Some(_) => false,
+            };
+            decoded && !["JAM", "WAI", "STP"].contains(&line[1])
+        }).map(|v| {
             v[2..4].to_ascii_lowercase()
-        }) {
-            let tests: Vec<Test> = serde_json::from_reader(ureq::get(&format!("{URL}{opcode}.json")).call().unwrap().into_reader()).unwrap();
-            for test in tests {
-                let mut cpu: Cpu = test.initial.clone().into();
-                let instruction = cpu.fetch();
-                cpu.execute(instruction);
-                let mut r#final: Cpu = test.r#final.clone().into();
-                r#final.clock = cpu.clock;
-                assert_eq!(cpu, r#final);
-                assert_eq!(cpu.clock.cpassed(), test.cycles.len() as u64);
+        }).collect();
+
+        // Each thread takes the next opcode until they run out.
+        let next = AtomicUsize::new(0);
+        let failures = Mutex::new(Vec::new());
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    while let Some(opcode) = opcodes.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let failed: Vec<String> = match vectors(&suite, opcode) {
+                            Err(error) => vec![error],
+                            Ok(tests) => tests.iter().filter_map(|test| {
+                                let diff = check(test, variant);
+                                (!diff.is_empty()).then(|| format!("{}:\n    {}", test.name, diff.join("\n    ")))
+                            }).collect(),
+                        };
+                        failures.lock().unwrap().extend(failed);
+                    }
+                });
+            }
+        });
+        let mut failures = failures.into_inner().unwrap();
+        failures.sort();
+        assert!(failures.is_empty(), "{} failing tests\n{}", failures.len(), failures.join("\n"));
+    }
+
+    /// Loads the test vectors of an opcode.
+    /// They're read from the directory in `M6502_TESTS` if it's set, which should be laid out like the ProcessorTests repository,
+    /// otherwise they're downloaded.
+    fn vectors(suite: &str, opcode: &str) -> Result<Vec<Test>, String> {
+        let path = format!("{suite}/v1/{opcode}.json");
+        let tests = match std::env::var_os("M6502_TESTS") {
+            Some(dir) => {
+                let file = std::fs::File::open(std::path::Path::new(&dir).join(&path)).map_err(|error| format!("{path}: {error}"))?;
+                serde_json::from_reader(std::io::BufReader::new(file))
+            }
+            None => {
+                let response = ureq::get(&format!("{URL}{path}")).call().map_err(|error| error.to_string())?;
+                serde_json::from_reader(response.into_reader())
+            }
+        };
+        tests.map_err(|error| format!("{path}: {error}"))
+    }
+
+    /// Runs a test vector on both cores, returns everything that doesn't match.
+    fn check(test: &Test, variant: Variant) -> Vec<String> {
+        let mut diff = Vec::new();
+        let expected: Cpu = test.r#final.clone().into();
+
+        let mut cpu: Cpu = test.initial.clone().into();
+        cpu.variant = variant;
+        match cpu.fetch().and_then(|instruction| cpu.execute(instruction)) {
+            Ok(_) => diff_state("execute", &cpu, &expected, &mut diff),
+            Err(error) => diff.push(format!("execute: {error}")),
+        }
+        if cpu.clock.cpassed() != test.cycles.len() as u64 {
+            diff.push(format!("execute: took {} cycles, expected {}", cpu.clock.cpassed(), test.cycles.len()));
+        }
+
+        // The cycle stepped core has to make exactly the bus accesses of the real chip.
+        let mut ticked: Cpu = test.initial.clone().into();
+        ticked.variant = variant;
+        loop {
+            match ticked.tick() {
+                Ok(_) if ticked.at_boundary() => break,
+                Ok(_) => (),
+                Err(error) => {
+                    diff.push(format!("tick: {error}"));
+                    return diff;
+                }
+            }
+        }
+        diff_state("tick", &ticked, &expected, &mut diff);
+        let accesses = std::mem::take(&mut ticked.bus.1);
+        let expected: Vec<_> = test.cycles.iter().map(|(addr, value, kind)| (*addr as u16, *value as u8, kind.as_str())).collect();
+        if accesses != expected {
+            diff.push(format!("tick: bus accesses {accesses:x?}, expected {expected:x?}"));
+        }
+        diff
+    }
+
+    /// Lists the registers and memory that differ from the expected state.
+    fn diff_state(core: &str, cpu: &Cpu, expected: &Cpu, diff: &mut Vec<String>) {
+        let registers = [
+            ("pc", cpu.pc, expected.pc),
+            ("s", cpu.sp as u16, expected.sp as u16),
+            ("a", cpu.accumulator as u16, expected.accumulator as u16),
+            ("x", cpu.x as u16, expected.x as u16),
+            ("y", cpu.y as u16, expected.y as u16),
+            ("p", cpu.status as u16, expected.status as u16),
+        ];
+        for (name, value, expected) in registers {
+            if value != expected {
+                diff.push(format!("{core}: {name} is {value:02x}, expected {expected:02x}"));
+            }
+        }
+        if cpu.bus != expected.bus {
+            for (addr, (value, expected)) in cpu.bus.0.iter().zip(expected.bus.0.iter()).enumerate() {
+                if value != expected {
+                    diff.push(format!("{core}: 0x{addr:04x} is {value:02x}, expected {expected:02x}"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decimal() {
+        // (accumulator, operand, carry in) => (accumulator, carry out, negative, zero)
+        let adc = [
+            ((0x58, 0x46, true), (0x05, true, true, false)),
+            ((0x12, 0x34, false), (0x46, false, false, false)),
+            ((0x81, 0x92, false), (0x73, true, false, false)),
+            // NMOS quirks: N comes from the unadjusted result and Z from the binary sum
+            ((0x99, 0x01, false), (0x00, true, true, false)),
+            ((0x50, 0x50, false), (0x00, true, true, false)),
+        ];
+        for ((a, value, carry), (result, carry_out, negative, zero)) in adc {
+            let cpu = run_decimal(0x69, a, value, carry);
+            assert_eq!((cpu.accumulator, cpu.carry(), cpu.negative(), cpu.zero()), (result, carry_out, negative, zero), "{a:02x} + {value:02x}");
+        }
+
+        let sbc = [
+            ((0x46, 0x12, true), (0x34, true)),
+            ((0x40, 0x13, true), (0x27, true)),
+            ((0x32, 0x02, false), (0x29, true)),
+            ((0x12, 0x21, true), (0x91, false)),
+        ];
+        for ((a, value, carry), (result, carry_out)) in sbc {
+            let cpu = run_decimal(0xe9, a, value, carry);
+            assert_eq!((cpu.accumulator, cpu.carry()), (result, carry_out), "{a:02x} - {value:02x}");
+        }
+
+        // The 2A03 ignores the decimal flag
+        let mut cpu: Cpu = State { pc: 0x0200, a: 0x09, p: 0x08, ram: vec![(0x0200, 0x69), (0x0201, 0x01)], ..Default::default() }.into();
+        cpu.variant = Variant::Ricoh2A03;
+        let instruction = cpu.fetch().unwrap();
+        cpu.execute(instruction).unwrap();
+        assert_eq!(cpu.accumulator, 0x0a);
+    }
+
+    #[test]
+    fn interrupts() {
+        let ram = vec![(0xFFFA, 0x00), (0xFFFB, 0x30), (0xFFFC, 0x00), (0xFFFD, 0x10), (0xFFFE, 0x00), (0xFFFF, 0x20)];
+        let mut cpu: Cpu = State { pc: 0x0400, s: 0xFF, p: 0x24, ram, ..Default::default() }.into();
+
+        // masked by the interrupt disable flag
+        cpu.set_irq(true);
+        assert_eq!(cpu.poll_interrupts(), None);
+        cpu.set_interrupt_disable(false);
+        assert_eq!(cpu.poll_interrupts(), Some(super::Interrupt::Irq));
+        assert_eq!(cpu.pc, 0x2000);
+        assert_eq!(cpu.sp, 0xFC);
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek_u16(0x01FE), 0x0400);
+        // the break flag is pushed clear
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek(0x01FD), 0x20);
+        assert!(cpu.interrupt_disable());
+        cpu.set_irq(false);
+
+        // NMI is edge triggered
+        cpu.set_nmi(true);
+        assert_eq!(cpu.poll_interrupts(), Some(super::Interrupt::Nmi));
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.poll_interrupts(), None);
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        assert_eq!(cpu.poll_interrupts(), Some(super::Interrupt::Nmi));
+
+        cpu.reset();
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.sp, 0xF3);
+        assert_eq!(cpu.clock.cpassed(), 28);
+    }
+
+    #[test]
+    fn brk() {
+        let ram = vec![(0x0400, 0x00), (0x0401, 0x42), (0xFFFE, 0x00), (0xFFFF, 0x20)];
+        let mut cpu: Cpu = State { pc: 0x0400, s: 0xFF, p: 0x20, ram, ..Default::default() }.into();
+
+        cpu.brk_policy = super::BrkPolicy::Halt;
+        assert_eq!(cpu.run().unwrap(), super::Stop::Brk);
+        assert_eq!((cpu.pc, cpu.sp), (0x0400, 0xFF));
+
+        cpu.brk_policy = super::BrkPolicy::Callback(|cpu| {
+            // the signature byte is the syscall number
+            cpu.accumulator = (&cpu.bus as &dyn super::Bus).peek(cpu.pc.wrapping_sub(1));
+            true
+        });
+        assert_eq!(cpu.run().unwrap(), super::Stop::Callback);
+        assert_eq!((cpu.pc, cpu.accumulator), (0x0402, 0x42));
+
+        cpu.pc = 0x0400;
+        cpu.brk_policy = super::BrkPolicy::Interrupt;
+        let instruction = cpu.fetch().unwrap();
+        assert_eq!(cpu.execute(instruction).unwrap(), None);
+        assert_eq!((cpu.pc, cpu.sp), (0x2000, 0xFC));
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek_u16(0x01FE), 0x0402);
+    }
+
+    #[test]
+    fn undocumented() {
+        // LAX <0x10; DCP <0x10; SBX #0x01; JAM
+        let ram = vec![(0x0200, 0xA7), (0x0201, 0x10), (0x0202, 0xC7), (0x0203, 0x10), (0x0204, 0xCB), (0x0205, 0x01), (0x0206, 0x02), (0x0010, 0x81)];
+        let mut cpu: Cpu = State { pc: 0x0200, p: 0x20, ram, ..Default::default() }.into();
+        assert_eq!(cpu.run().unwrap(), super::Stop::Jam);
+        assert_eq!(cpu.pc, 0x0206);
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek(0x0010), 0x80);
+        assert_eq!((cpu.accumulator, cpu.x), (0x81, 0x80));
+        assert!(cpu.carry() && cpu.negative());
+        assert_eq!(cpu.clock.cpassed(), 3 + 5 + 2);
+    }
+
+    #[test]
+    fn cmos() {
+        // JMP (0x02FF) reads its high byte from 0x0300 instead of 0x0200
+        let ram = vec![(0x0400, 0x6C), (0x0401, 0xFF), (0x0402, 0x02), (0x02FF, 0x00), (0x0300, 0x05), (0x0200, 0x06)];
+        let mut cpu: Cpu = State { pc: 0x0400, p: 0x20, ram, ..Default::default() }.into();
+        let instruction = cpu.fetch().unwrap();
+        cpu.execute(instruction).unwrap();
+        assert_eq!(cpu.pc, 0x0600);
+
+        cpu.variant = Variant::Wdc65C02;
+        cpu.pc = 0x0400;
+        let instruction = cpu.fetch().unwrap();
+        cpu.execute(instruction).unwrap();
+        assert_eq!(cpu.pc, 0x0500);
+        assert_eq!(cpu.clock.cpassed(), 5 + 6);
+
+        // STZ <0x10; LDA #0x0F; TSB <0x11; INC A; LDA (0x12); WAI
+        let ram = vec![(0x0200, 0x64), (0x0201, 0x10), (0x0202, 0xA9), (0x0203, 0x0F), (0x0204, 0x04), (0x0205, 0x11), (0x0206, 0x1A), (0x0207, 0xB2), (0x0208, 0x12), (0x0209, 0xCB), (0x0010, 0xFF), (0x0011, 0xF0), (0x0012, 0x11), (0x0013, 0x00)];
+        let mut cpu: Cpu = State { pc: 0x0200, p: 0x20, ram, ..Default::default() }.into();
+        cpu.variant = Variant::Wdc65C02;
+        assert_eq!(cpu.run().unwrap(), super::Stop::Wai);
+        assert_eq!(cpu.run().unwrap(), super::Stop::Wai);
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek(0x0010), 0x00);
+        assert_eq!((&cpu.bus as &dyn super::Bus).peek(0x0011), 0xFF);
+        assert_eq!(cpu.accumulator, 0xFF);
+        assert_eq!(cpu.pc, 0x020A);
+
+        // a masked IRQ ends the wait without being taken
+        cpu.set_interrupt_disable(true);
+        cpu.set_irq(true);
+        cpu.poll_interrupts();
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.pc, 0x020A);
+
+        // N and Z are valid in decimal mode and it costs an extra cycle
+        let mut cpu: Cpu = State { pc: 0x0200, a: 0x99, p: 0x28, ram: vec![(0x0200, 0x69), (0x0201, 0x01)], ..Default::default() }.into();
+        cpu.variant = Variant::Wdc65C02;
+        let instruction = cpu.fetch().unwrap();
+        cpu.execute(instruction).unwrap();
+        assert_eq!((cpu.accumulator, cpu.carry(), cpu.negative(), cpu.zero()), (0x00, true, false, true));
+        assert_eq!(cpu.clock.cpassed(), 3);
+    }
+
+    #[test]
+    fn bit_instructions() {
+        // Runs one instruction on both cores, returns the cpu and the cycles it took.
+        fn run(pc: u16, code: [u8; 3], value: u8) -> (Cpu, u64) {
+            let ram = vec![(pc, code[0]), (pc + 1, code[1]), (pc + 2, code[2]), (0x0010, value)];
+            let mut ticked: Cpu = State { pc, p: 0x20, ram: ram.clone(), ..Default::default() }.into();
+            ticked.variant = Variant::Wdc65C02;
+            ticked.tick().unwrap();
+            while !ticked.at_boundary() {
+                ticked.tick().unwrap();
+            }
+            let mut cpu: Cpu = State { pc, p: 0x20, ram, ..Default::default() }.into();
+            cpu.variant = Variant::Wdc65C02;
+            let instruction = cpu.fetch().unwrap();
+            cpu.execute(instruction).unwrap();
+            assert_eq!((ticked.pc, ticked.cycles), (cpu.pc, cpu.clock.cpassed()), "{code:02x?} on {value:02x}");
+            assert_eq!((&ticked.bus as &dyn super::Bus).peek(0x0010), (&cpu.bus as &dyn super::Bus).peek(0x0010));
+            let cycles = cpu.clock.cpassed();
+            (cpu, cycles)
+        }
+
+        for bit in 0..8 {
+            let mask = 1 << bit;
+            // RMBn <0x10 and SMBn <0x10 only touch their own bit
+            let (cpu, cycles) = run(0x0200, [0x07 | bit << 4, 0x10, 0x00], 0xFF);
+            assert_eq!(((&cpu.bus as &dyn super::Bus).peek(0x0010), cpu.pc, cycles), (!mask, 0x0202, 5));
+            let (cpu, cycles) = run(0x0200, [0x87 | bit << 4, 0x10, 0x00], 0x00);
+            assert_eq!(((&cpu.bus as &dyn super::Bus).peek(0x0010), cpu.pc, cycles), (mask, 0x0202, 5));
+
+            // BBRn <0x10, +4 and BBSn <0x10, +4, taken for a clear and a set bit respectively
+            for (code, value, taken) in [(0x0F, !mask, true), (0x0F, mask, false), (0x8F, mask, true), (0x8F, !mask, false)] {
+                let (cpu, cycles) = run(0x0200, [code | bit << 4, 0x10, 0x04], value);
+                let expected = if taken { (0x0207, 6) } else { (0x0203, 5) };
+                assert_eq!((cpu.pc, cycles), expected, "{:02x} on {value:02x}", code | bit << 4);
+            }
+        }
+
+        // crossing a page forwards or backwards costs another cycle, but only when the branch is taken
+        for (pc, code, value, target, expected) in [(0x02FB, 0x8F, 0x01, 0x10, (0x030E, 7)), (0x0300, 0x0F, 0x00, 0xF0, (0x02F3, 7)), (0x02FB, 0x8F, 0x00, 0x10, (0x02FE, 5))] {
+            let (cpu, cycles) = run(pc, [code, 0x10, target], value);
+            assert_eq!((cpu.pc, cycles), expected);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        // 0x02 locks up the NMOS core but is a NOP on the 65C02, 0x8B isn't decoded on the NMOS core
+        let mut cpu: Cpu = State { pc: 0x0200, ram: vec![(0x0200, 0x02), (0x0201, 0x00), (0x0202, 0x8B)], ..Default::default() }.into();
+        assert_eq!(cpu.run(), Ok(super::Stop::Jam));
+        cpu.pc = 0x0202;
+        assert_eq!(cpu.run(), Err(super::Error::IllegalOpcode { pc: 0x0202, opcode: 0x8B }));
+
+        cpu.variant = Variant::Wdc65C02;
+        cpu.pc = 0x0200;
+        let instruction = cpu.fetch().unwrap();
+        assert_eq!(cpu.pc, 0x0202);
+        let mut invalid = instruction;
+        invalid.opcode = super::Opcode::JSR;
+        assert_eq!(cpu.execute(invalid), Err(super::Error::InvalidOperand { opcode: super::Opcode::JSR, addr: instruction.addr }));
+
+        // the error points at the opcode, fetching stops right after an illegal one
+        cpu.variant = Variant::Nmos;
+        cpu.pc = 0x0301;
+        let illegal = super::Instruction { opcode: super::Opcode::NOP, addr: super::Address::Implied, code: 0x8B };
+        assert_eq!(cpu.execute(illegal), Err(super::Error::IllegalOpcode { pc: 0x0300, opcode: 0x8B }));
+    }
+
+    #[test]
+    fn wrapping() {
+        // JSR 0x0200 with its last byte at 0xFFFF, the RTS pops 0xFFFF and returns to 0x0000
+        let ram = vec![(0xFFFD, 0x20), (0xFFFE, 0x00), (0xFFFF, 0x02), (0x0200, 0x60)];
+        let mut cpu: Cpu = State { pc: 0xFFFD, s: 0xFF, ram, ..Default::default() }.into();
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.sp, cpu.bus.0[0x01FF], cpu.bus.0[0x01FE]), (0x0200, 0xFD, 0xFF, 0xFF));
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.sp), (0x0000, 0xFF));
+    }
+
+    #[test]
+    fn step() {
+        // LDA 0x12FF,x; BNE -2 (taken, to another page)
+        let ram = vec![(0x10FC, 0xBD), (0x10FD, 0xFF), (0x10FE, 0x12), (0x10FF, 0xD0), (0x1100, 0xFE), (0x1301, 0x01), (0xFFFE, 0xFC), (0xFFFF, 0x10)];
+        let mut cpu: Cpu = State { pc: 0x0400, s: 0xFF, x: 0x02, p: 0x20, ram, ..Default::default() }.into();
+        cpu.set_irq(true);
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.interrupt, Some(super::Interrupt::Irq));
+        assert_eq!(step.pc, 0x10FC);
+        assert_eq!(step.instruction.map(|v| v.opcode), Some(super::Opcode::LDA));
+        assert_eq!(step.cycles, 7 + 5);
+        assert_eq!(step.stop, None);
+        cpu.set_irq(false);
+
+        let step = cpu.step().unwrap();
+        assert_eq!((step.pc, step.cycles, step.interrupt), (0x10FF, 4, None));
+        assert_eq!(cpu.pc, 0x10FF);
+        assert_eq!(cpu.cycles, 16);
+        assert_eq!(cpu.clock.cpassed(), 16);
+    }
+
+    #[test]
+    fn run_for_cycles() {
+        // loop: JMP loop
+        let ram = vec![(0x0200, 0x4C), (0x0201, 0x00), (0x0202, 0x02)];
+        let mut cpu: Cpu = State { pc: 0x0200, p: 0x20, ram, ..Default::default() }.into();
+        let mut budget = 0;
+        for n in [10, 10, 1, 2, 100] {
+            budget += n;
+            assert_eq!(cpu.run_for_cycles(n), Ok(None));
+            assert_eq!(cpu.cycles - cpu.overshoot, budget);
+            assert!(cpu.overshoot < 3);
+        }
+
+        // INX; JMP 0x0200
+        let ram = vec![(0x0200, 0xE8), (0x0201, 0x4C), (0x0202, 0x00), (0x0203, 0x02)];
+        let mut cpu: Cpu = State { pc: 0x0200, p: 0x20, ram, ..Default::default() }.into();
+        assert_eq!(cpu.run_until(|cpu| cpu.x == 10), Ok(None));
+        assert_eq!((cpu.x, cpu.pc, cpu.cycles), (10, 0x0201, 10 * 2 + 9 * 3));
+    }
+
+    #[test]
+    fn snapshot() {
+        let ram = vec![(0x0200, 0xE8), (0x0201, 0x4C), (0x0202, 0x00), (0x0203, 0x02)];
+        // the IRQ is masked, but the level of the line is saved
+        let mut cpu: Cpu = State { pc: 0x0200, p: 0x24, ram, ..Default::default() }.into();
+        cpu.variant = Variant::Wdc65C02;
+        cpu.set_irq(true);
+        cpu.run_for_cycles(100).unwrap();
+        let state = cpu.save_state();
+
+        let mut restored: Cpu = State::default().into();
+        restored.load_state(&state).unwrap();
+        restored.clock = cpu.clock;
+        assert_eq!(restored, cpu);
+
+        let path = std::env::temp_dir().join(format!("m6502-snapshot-{}", std::process::id()));
+        cpu.save_state_to(&path).unwrap();
+        cpu.run_for_cycles(100).unwrap();
+        assert_ne!(restored, cpu);
+        cpu.load_state_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        cpu.clock = restored.clock;
+        assert_eq!(restored, cpu);
+
+        assert!(matches!(restored.load_state(b"nope"), Err(super::SnapshotError::Magic)));
+        assert!(matches!(restored.load_state(&state[..20]), Err(super::SnapshotError::Truncated)));
+        let trailing = [state.as_slice(), &[0]].concat();
+        assert!(matches!(restored.load_state(&trailing), Err(super::SnapshotError::Invalid(_))));
+
+        // a save taken in the middle of an instruction finishes it after restoring
+        cpu.tick().unwrap();
+        assert!(!cpu.at_boundary());
+        let state = cpu.save_state();
+        restored.load_state(&state).unwrap();
+        assert!(!restored.at_boundary());
+        while !cpu.at_boundary() {
+            cpu.tick().unwrap();
+            restored.tick().unwrap();
+        }
+        restored.clock = cpu.clock;
+        assert_eq!(restored, cpu);
+        // and restoring a save taken between instructions drops the progress
+        restored.tick().unwrap();
+        restored.load_state(&cpu.save_state()).unwrap();
+        assert!(restored.at_boundary());
+    }
+
+    #[test]
+    fn tick() {
+        // Every instruction takes as many ticks as `step` says it takes and leaves the same state behind.
+        let mut seed = 0x2545F491u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        let mut memory = Bus::new();
+        memory.0.iter_mut().for_each(|byte| *byte = random());
+        fn callback(cpu: &mut Cpu) -> bool {
+            cpu.x = cpu.x.wrapping_add(1);
+            false
+        }
+        let policies = [super::BrkPolicy::Interrupt, super::BrkPolicy::Halt, super::BrkPolicy::Callback(callback)];
+        let variants = [Variant::Nmos, Variant::Ricoh2A03, Variant::Wdc65C02];
+        for (variant, brk_policy) in variants.into_iter().flat_map(|variant| policies.map(|policy| (variant, policy))) {
+            for opcode in 0..=0xFF {
+                for _ in 0..8 {
+                    let mut bus = Bus(memory.0.clone(), Vec::new());
+                    bus.0[0x0200..0x0203].copy_from_slice(&[opcode, random(), random()]);
+                    let (x, y, status, accumulator, sp) = (random(), random(), random(), random(), random());
+                    let new = |bus| {
+                        let mut cpu = super::Cpu::with_state(bus, Clock::new(), x, y, status, accumulator, sp, 0x0200);
+                        cpu.variant = variant;
+                        cpu.brk_policy = brk_policy;
+                        cpu
+                    };
+                    let mut ticked = new(Bus(bus.0.clone(), Vec::new()));
+                    let mut cpu = new(bus);
+
+                    let step = match cpu.step() {
+                        Ok(step) => step,
+                        Err(error) => {
+                            assert_eq!(ticked.tick(), Err(error));
+                            continue;
+                        }
+                    };
+                    // JAM doesn't take any cycles in `execute`
+                    if step.stop == Some(super::Stop::Jam) {
+                        continue;
+                    }
+                    let mut ncycles = 0;
+                    let stop = loop {
+                        ncycles += 1;
+                        let stop = ticked.tick().unwrap();
+                        if ticked.at_boundary() {
+                            break stop;
+                        }
+                    };
+                    let name = format!("{:?} {:?}", variant, step.instruction);
+                    assert_eq!((ncycles, stop), (step.cycles, step.stop), "{name}");
+                    assert_eq!(ticked, cpu, "{name}");
+                }
+            }
+        }
+
+        // The NMOS core writes the old value back before the result, the 65C02 reads it twice instead.
+        for (variant, accesses) in [(Variant::Nmos, [(0x10, 0x7F, false), (0x10, 0x7F, true), (0x10, 0x80, true)]), (Variant::Wdc65C02, [(0x10, 0x7F, false), (0x10, 0x7F, false), (0x10, 0x80, true)])] {
+            // INC 0x10
+            let mut cpu: Cpu = State { pc: 0x0200, ram: vec![(0x0200, 0xE6), (0x0201, 0x10), (0x0010, 0x7F)], ..Default::default() }.into();
+            cpu.variant = variant;
+            cpu.tick().unwrap();
+            cpu.tick().unwrap();
+            for (addr, value, write) in accesses {
+                let before = cpu.bus.0[addr as usize];
+                cpu.tick().unwrap();
+                assert_eq!(cpu.bus.0[addr as usize], if write { value } else { before });
+            }
+            assert!(cpu.at_boundary());
+            assert!(cpu.negative());
+        }
+
+        // An IRQ raised in the middle of an instruction is taken after it.
+        let ram = vec![(0x0200, 0xEE), (0x0201, 0x00), (0x0202, 0x30), (0xFFFE, 0x00), (0xFFFF, 0x20)];
+        let mut cpu: Cpu = State { pc: 0x0200, s: 0xFF, p: 0x20, ram, ..Default::default() }.into();
+        cpu.tick().unwrap();
+        cpu.set_irq(true);
+        while !cpu.at_boundary() {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.bus.0[0x3000], 1);
+        for _ in 0..7 {
+            cpu.tick().unwrap();
+        }
+        assert!(cpu.at_boundary());
+        assert_eq!((cpu.pc, cpu.sp, cpu.cycles), (0x2000, 0xFC, 6 + 7));
+
+        // The lines are polled on the second-to-last cycle, an NMI raised on the last cycle of LDA 0x3000 waits for the
+        // NOP after it.
+        for (ticks, cycles, return_addr) in [(2, 4, 0x0203), (3, 4 + 2, 0x0204)] {
+            let ram = vec![(0x0200, 0xAD), (0x0201, 0x00), (0x0202, 0x30), (0x0203, 0xEA), (0xFFFA, 0x00), (0xFFFB, 0x20)];
+            let mut cpu: Cpu = State { pc: 0x0200, s: 0xFF, p: 0x20, ram, ..Default::default() }.into();
+            for _ in 0..ticks {
+                cpu.tick().unwrap();
+            }
+            cpu.set_nmi(true);
+            for _ in ticks..cycles + 7 {
+                cpu.tick().unwrap();
+            }
+            assert!(cpu.at_boundary());
+            assert_eq!((cpu.pc, cpu.nmi_pending), (0x2000, false));
+            assert_eq!(u16::from_le_bytes([cpu.bus.0[0x01FE], cpu.bus.0[0x01FF]]), return_addr);
+        }
+    }
+
+    #[test]
+    fn trap() {
+        // LDX #3; DEX; BNE -3; JMP 0x0205
+        let image = [0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0x02];
+        let mut cpu = super::Cpu::new(super::FlatBus::from_image(&image, 0x0200), ());
+        let trap = cpu.run_to_trap(1000, 3, |_| ());
+        assert_eq!((trap.pc, trap.reason), (0x0205, super::TrapReason::Loop));
+        assert_eq!(trap.trace.iter().map(|step| step.pc).collect::<Vec<_>>(), [0x0202, 0x0203, 0x0205]);
+
+        // BNE -2 with the zero flag clear never gets anywhere
+        let mut cpu = super::Cpu::new(super::FlatBus::from_image(&[0xD0, 0xFE], 0x0200), ());
+        assert_eq!(cpu.run_to_trap(1000, 0, |_| ()).reason, super::TrapReason::Loop);
+        let mut cpu = super::Cpu::new(super::FlatBus::from_image(&[0xE8, 0x4C, 0x00, 0x02], 0x0200), ());
+        assert_eq!(cpu.run_to_trap(1000, 0, |_| ()).reason, super::TrapReason::Limit);
+    }
+
+    /// Runs Klaus Dormann's test binaries from the directory in `M6502_DORMANN`.
+    /// The addresses are those of the binaries in the `bin_files` directory of the repository, which are loaded at 0.
+    /// The decimal test isn't among them, it's expected assembled with its defaults, the code at 0x0200 and ERROR at
+    /// 0x000B, and with `end_of_test` jumping to itself since 0xDB only stops the 65C02.
+    #[test]
+    #[ignore = "needs Klaus Dormann's test binaries in the directory in M6502_DORMANN"]
+    fn dormann() {
+        let dir = std::env::var_os("M6502_DORMANN").expect("M6502_DORMANN isn't set");
+        // How a binary reports success, by trapping at an address or by clearing its ERROR byte.
+        enum Success {
+            Trap(u16),
+            Error(u16),
+        }
+        // (file, variant, start, success)
+        let binaries = [
+            ("6502_functional_test.bin", Variant::Nmos, 0x0400, Success::Trap(0x3469)),
+            ("6502_decimal_test.bin", Variant::Nmos, 0x0200, Success::Error(0x000B)),
+            ("65C02_extended_opcodes_test.bin", Variant::Wdc65C02, 0x0400, Success::Trap(0x24F1)),
+            ("6502_interrupt_test.bin", Variant::Nmos, 0x0400, Success::Trap(0x06F5)),
+        ];
+        for (file, variant, start, success) in binaries {
+            let path = std::path::Path::new(&dir).join(file);
+            let image = std::fs::read(&path).unwrap_or_else(|error| panic!("{}: {error}", path.display()));
+            let mut cpu = super::Cpu::new(super::FlatBus::from_image(&image, 0), ());
+            cpu.variant = variant;
+            cpu.brk_policy = super::BrkPolicy::Interrupt;
+            cpu.pc = start;
+            // The interrupt test drives IRQ and NMI through bits 0 and 1 of a feedback register.
+            let trap = cpu.run_to_trap(200_000_000, 32, |cpu| {
+                let feedback = cpu.bus.0[0xBFFC];
+                cpu.set_irq(feedback & 0x01 != 0);
+                cpu.set_nmi(feedback & 0x02 != 0);
+            });
+            match success {
+                Success::Trap(addr) => assert!(trap.reason == super::TrapReason::Loop && trap.pc == addr, "{file}: {trap}"),
+                Success::Error(addr) => {
+                    let error = cpu.bus.0[addr as usize];
+                    let finished = matches!(trap.reason, super::TrapReason::Loop | super::TrapReason::Stop(_));
+                    assert!(finished && error == 0, "{file}: ERROR is 0x{error:02x}, {trap}");
+                }
             }
         }
-    
+    }
+
+    #[test]
+    fn throttle() {
+        use super::Clock;
+
+        // 50ms worth of cycles at 1MHz, synced in small uneven steps like instructions would
+        let mut throttle = super::Throttle::new(1_000_000, 1_000);
+        let start = std::time::Instant::now();
+        let mut cycles = 0;
+        while cycles < 50_000 {
+            cycles += 2 + cycles % 5;
+            throttle.sync(cycles);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(49), "{elapsed:?}");
+        assert!(elapsed < std::time::Duration::from_millis(500), "{elapsed:?}");
+        assert!(throttle.drift(cycles) < 0.002);
+
+        // falling far behind doesn't make it run flat out afterwards
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        throttle.sync(cycles + 1_000);
+        assert!(throttle.drift(cycles + 1_000) > -0.1);
+
+        // going back to an earlier cycle count, like restoring a save state does, paces from there
+        assert_eq!(throttle.drift(10_000), 0.0);
+        throttle.sync(10_000);
+        let start = std::time::Instant::now();
+        for cycles in (10_000..=30_000).step_by(100) {
+            throttle.sync(cycles);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(19), "{elapsed:?}");
+        assert!(elapsed < std::time::Duration::from_millis(500), "{elapsed:?}");
+        assert!(throttle.drift(30_000).abs() < 0.01);
+    }
+
+    #[test]
+    fn peek() {
+        use super::Bus as _;
+
+        /// A device with a status register at 0x8000 that clears when the cpu reads it.
+        struct Device(super::FlatBus, u8);
+        impl super::Bus for Device {
+            fn load(&mut self, addr: u16) -> u8 {
+                match addr {
+                    0x8000 => std::mem::take(&mut self.1),
+                    addr => self.0.load(addr),
+                }
+            }
+
+            fn store(&mut self, addr: u16, value: u8) {
+                self.0.store(addr, value)
+            }
+
+            fn peek(&self, addr: u16) -> u8 {
+                match addr {
+                    0x8000 => self.1,
+                    addr => self.0.peek(addr),
+                }
+            }
+        }
+
+        // LDA $8000; LDA $8000
+        let program = [0xAD, 0x00, 0x80, 0xAD, 0x00, 0x80];
+        let mut cpu = super::Cpu::new(Device(super::FlatBus::from_image(&program, 0x0400), 0x81), ());
+        cpu.pc = 0x0400;
+        assert_eq!(cpu.bus.peek(0x8000), 0x81);
+        assert_eq!(cpu.bus.peek(0x8000), 0x81);
+        assert_eq!(cpu.bus.peek_u16(0x0401), 0x8000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0x81);
+        assert_eq!(cpu.bus.peek(0x8000), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator, 0);
+    }
+
+    #[test]
+    fn memory_map() {
+        use super::{AccessKind, Bus as _, Fault, MemoryMap, Unmapped};
+        use std::sync::{Arc, Mutex};
+
+        let device = Arc::new(Mutex::new([0u8; 4]));
+        let mut bus = MemoryMap::new()
+            .ram(0x0000..=0x07ff)
+            .mirror(0x0800..=0x1fff, 0x0000..=0x07ff)
+            .device(0x2000..=0x2003, device.clone())
+            .mirror(0x2004..=0x3fff, 0x2000..=0x2003)
+            .rom(0xfffc, &[0x00, 0x80, 0x34, 0x12]);
+
+        bus.store(0x1801, 0xaa);
+        assert_eq!(bus.load(0x0001), 0xaa);
+        assert_eq!(bus.peek(0x0801), 0xaa);
+        bus.store(0x3ffe, 0x55);
+        assert_eq!(device.lock().unwrap()[2], 0x55);
+        bus.store(0xfffc, 0xff);
+        assert_eq!(bus.peek_u16(0xfffc), 0x8000);
+
+        // open bus returns the last value read or written
+        assert_eq!(bus.load(0xfffe), 0x34);
+        assert_eq!(bus.load(0x5000), 0x34);
+        assert_eq!(bus.take_fault(), None);
+
+        let mut bus = bus.unmapped(Unmapped::Fault);
+        assert_eq!(bus.load(0x5000), 0);
+        bus.store(0x6000, 1);
+        assert_eq!(bus.take_fault(), Some(Fault { addr: 0x5000, kind: AccessKind::Read }));
+        assert_eq!(bus.take_fault(), None);
+
+        // the devices are saved along with the RAM
+        let mut state = Vec::new();
+        super::Snapshot::save_state(&bus, &mut state);
+        bus.store(0x0001, 0);
+        bus.store(0x2002, 0);
+        super::Snapshot::load_state(&mut bus, &state).unwrap();
+        assert_eq!((bus.peek(0x0001), device.lock().unwrap()[2]), (0xaa, 0x55));
+        assert!(super::Snapshot::load_state(&mut bus, &state[..state.len() - 1]).is_err());
+
+        // the RAM is restored before the device, it's put back when the device rejects its state
+        let mut rejected = state[..state.len() - 8].to_vec();
+        rejected[2] = 0x11;
+        rejected.extend_from_slice(&3u32.to_le_bytes());
+        rejected.extend_from_slice(&[1, 2, 3]);
+        assert!(super::Snapshot::load_state(&mut bus, &rejected).is_err());
+        assert_eq!((bus.peek(0x0001), device.lock().unwrap()[2]), (0xaa, 0x55));
+    }
+
+    #[test]
+    fn open_bus() {
+        use super::{AccessKind, Bus as _, MemoryMap, UnmappedAccess};
+
+        /// A controller port that only drives the lowest 5 bits, like the NES one.
+        struct Controller;
+        impl super::Device for Controller {
+            fn load(&mut self, _: u16) -> u8 {
+                0x01
+            }
+
+            fn store(&mut self, _: u16, _: u8) {}
+
+            fn driven(&self, _: u16) -> u8 {
+                0x1f
+            }
+        }
+
+        for ticked in [false, true] {
+            let mut bus = MemoryMap::new().ram(0x0000..=0x07ff).device(0x4016..=0x4016, Controller).log_unmapped(true);
+            // LDA $5000; LDA $4016
+            for (i, byte) in [0xAD, 0x00, 0x50, 0xAD, 0x16, 0x40].into_iter().enumerate() {
+                bus.store(0x0400 + i as u16, byte);
+            }
+            let mut cpu = super::Cpu::new(bus, ());
+            cpu.pc = 0x0400;
+            let step = |cpu: &mut super::Cpu<MemoryMap, ()>| {
+                if ticked {
+                    cpu.tick().unwrap();
+                    while !cpu.at_boundary() {
+                        cpu.tick().unwrap();
+                    }
+                } else {
+                    cpu.step().unwrap();
+                }
+            };
+
+            // the high byte of the address is the last value on the bus
+            step(&mut cpu);
+            assert_eq!(cpu.accumulator, 0x50);
+            step(&mut cpu);
+            assert_eq!(cpu.accumulator, 0x41);
+            assert_eq!(cpu.bus.data_bus(), 0x41);
+            assert_eq!(cpu.bus.take_unmapped_log(), [UnmappedAccess { addr: 0x5000, kind: AccessKind::Read, value: 0x50 }]);
+            assert!(cpu.bus.take_unmapped_log().is_empty());
+        }
+    }
+
+    #[test]
+    fn bus_fault() {
+        use super::{AccessKind, Bus as _, Error, FaultPolicy, MemoryMap, Unmapped};
+
+        let new = || {
+            // STA $0300; LDA $5000
+            let mut bus = MemoryMap::new().ram(0x0000..=0x07ff).protect(0x0300..=0x03ff).unmapped(Unmapped::Fault);
+            for (i, byte) in [0x8D, 0x00, 0x03, 0xAD, 0x00, 0x50].into_iter().enumerate() {
+                bus.store(0x0400 + i as u16, byte);
+            }
+            let mut cpu = super::Cpu::new(bus, ());
+            cpu.pc = 0x0400;
+            cpu.accumulator = 0x42;
+            cpu
+        };
+
+        let mut cpu = new();
+        assert_eq!(cpu.step(), Err(Error::BusFault { pc: 0x0400, addr: 0x0300, kind: AccessKind::Write }));
+        assert_eq!(cpu.bus.peek(0x0300), 0);
+        assert_eq!(cpu.step(), Err(Error::BusFault { pc: 0x0403, addr: 0x5000, kind: AccessKind::Read }));
+
+        // execute reports the fault itself rather than leaving it for the next step
+        let mut cpu = new();
+        let instruction = cpu.fetch().unwrap();
+        assert_eq!(cpu.execute(instruction), Err(Error::BusFault { pc: 0x0400, addr: 0x0300, kind: AccessKind::Write }));
+        cpu.bus.store(0x0404, 0x00);
+        cpu.bus.store(0x0405, 0x02);
+        assert!(cpu.step().is_ok());
+
+        let mut cpu = new();
+        cpu.tick().unwrap();
+        while !cpu.at_boundary() {
+            match cpu.tick() {
+                Ok(_) => assert!(!cpu.at_boundary()),
+                Err(error) => {
+                    assert_eq!(error, Error::BusFault { pc: 0x0400, addr: 0x0300, kind: AccessKind::Write });
+                    break;
+                }
+            }
+        }
+        assert!(cpu.at_boundary());
+
+        // the callback can let the guest carry on
+        let mut cpu = new();
+        cpu.fault_policy = FaultPolicy::Callback(|cpu, fault| {
+            cpu.x += 1;
+            fault.kind == AccessKind::Read
+        });
+        assert!(cpu.step().is_ok());
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.x, 2);
+    }
+
+    #[test]
+    fn disassembler() {
+        use super::{Address, Disassembler, FlatBus, Instruction, Opcode, Variant};
+
+        let code = [
+            0xA9, 0x03, // lda #0x03
+            0xB5, 0x10, // lda <0x10, x
+            0x91, 0x20, // sta (0x20), y
+            0x9D, 0x00, 0xfe, // sta 0xfe00, x
+            0x0A, // asl a
+            0x6C, 0x34, 0x12, // jmp (0x1234)
+            0xD0, 0xF1, // bne 0x0400
+            0x20, 0x00, 0x04, // jsr 0x0400
+            0x02, // jam
+            0x4C, 0x00, // cut off
+        ];
+        let disassembler = Disassembler::new(Variant::Nmos).label(0x0400, "main").label(0x0020, "pointer");
+        let lines = disassembler.disassemble(&code, 0x0400);
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, [
+            "lda #0x03",
+            "lda <0x10, x",
+            "sta (pointer), y",
+            "sta 0xfe00, x",
+            "asl a",
+            "jmp (0x1234)",
+            "bne main",
+            "jsr main",
+            "jam",
+            "#d8 0x4c",
+            "brk",
+        ]);
+        assert_eq!(lines[0].to_string(), "main:\n0x0400  a9 03     lda #0x03");
+        assert_eq!(lines[3].bytes, [0x9D, 0x00, 0xfe]);
+
+        let cmos = Disassembler::new(Variant::Wdc65C02);
+        assert_eq!(cmos.line(0x0400, &[0x02, 0x00]).text, "nop #0x00");
+        assert_eq!(cmos.line(0x0400, &[0x0F, 0x10, 0xFD]).text, "bbr0 <0x10, 0x0400");
+        assert_eq!(cmos.line(0x0400, &[0xB2, 0x10]).text, "lda (0x10)");
+
+        // absolute operands that would assemble to zero page are written as bytes
+        let nmos = super::Assembler::new(Variant::Nmos);
+        for (bytes, text) in [
+            ([0xAD, 0x10, 0x00], "#d8 0xad, 0x10, 0x00 ; lda 0x0010"),
+            ([0xBE, 0xff, 0x00], "#d8 0xbe, 0xff, 0x00 ; ldx 0x00ff, y"),
+            ([0xB9, 0x10, 0x00], "lda 0x0010, y"),
+            ([0x20, 0x10, 0x00], "jsr 0x0010"),
+        ] {
+            let line = disassembler.line(0x0400, &bytes);
+            assert_eq!(line.text, text);
+            assert_eq!(line.instruction, Instruction::decode(Variant::Nmos, &bytes));
+            assert_eq!(nmos.line(text, 0x0400).unwrap(), bytes);
+        }
+
+        // without an address the branch target is relative to the instruction
+        let instruction = Instruction::decode(Variant::Nmos, &[0xD0, 0xF1]).unwrap();
+        assert_eq!(instruction, Instruction { opcode: Opcode::BNE, addr: Address::Relative(0xF1), code: 0xD0 });
+        assert_eq!(instruction.to_string(), "bne $ - 13");
+        assert_eq!(Instruction::decode(Variant::Nmos, &[0xD0]), None);
+
+        let bus = FlatBus::from_image(&code, 0x0400);
+        assert_eq!(disassembler.disassemble_bus(&bus, 0x0400..=0x0409), lines[..5]);
+    }
+
+    #[test]
+    fn analysis() {
+        use super::{Analysis, Variant};
+
+        let code = [
+            0x20, 0x0A, 0x04, // 0x0400 jsr 0x040a
+            0xD0, 0xFB, // 0x0403 bne 0x0400
+            0x60, // 0x0405 rts
+            0xFF, 0xFF, 0xFF, 0xFF, // 0x0406 data
+            0xA2, 0x00, // 0x040a ldx #0x00
+            0xE8, // 0x040c inx
+            0xD0, 0xFD, // 0x040d bne 0x040c
+            0x6C, 0x34, 0x12, // 0x040f jmp (0x1234)
+        ];
+        let analysis = Analysis::new(Variant::Nmos, &code, 0x0400, &[0x0400]);
+        assert_eq!(analysis.blocks.keys().copied().collect::<Vec<_>>(), [0x0400, 0x0405, 0x040a, 0x040c, 0x040f]);
+        assert_eq!(analysis.blocks[&0x0400].instructions, [0x0400, 0x0403]);
+        assert_eq!(analysis.blocks[&0x0400].calls, [0x040a]);
+        assert_eq!(analysis.blocks[&0x0400].successors, [0x0400, 0x0405]);
+        assert_eq!(analysis.blocks[&0x040c].successors, [0x040c, 0x040f]);
+        assert!(analysis.blocks[&0x040f].indirect);
+        assert!(analysis.is_code(0x0401) && analysis.is_code(0x0410));
+        assert!(!analysis.is_code(0x0406) && !analysis.is_code(0x0412));
+
+        assert_eq!(analysis.functions.keys().copied().collect::<Vec<_>>(), [0x0400, 0x040a]);
+        assert_eq!(analysis.functions[&0x0400].blocks.iter().copied().collect::<Vec<_>>(), [0x0400, 0x0405]);
+        assert_eq!(analysis.functions[&0x040a].callers.iter().copied().collect::<Vec<_>>(), [0x0400]);
+
+        let listing = analysis.listing();
+        assert!(listing.contains("0x0400  20 0a 04  jsr sub_040a\n"), "{listing}");
+        assert!(listing.contains("0x0406            #d8 0xff, 0xff, 0xff, 0xff\n"), "{listing}");
+        assert!(listing.contains("jmp (0x1234) ; indirect, not followed"), "{listing}");
+        assert!(analysis.cfg_dot().contains("\"loc_040c\" -> \"loc_040f\""));
+        assert!(analysis.calls_dot().contains("\"sub_0400\" -> \"sub_040a\""));
+    }
+
+    #[test]
+    fn assembler() {
+        use super::{Address, AsmError, AsmErrorKind, Assembler, Instruction, Opcode, Variant};
+
+        // customasm fills the output from address 0
+        let expected = include_bytes!(concat!(env!("OUT_DIR"), "/assembler_test"));
+        let assembly = Assembler::default().assemble(include_str!("asm/assembler_test.asm")).unwrap();
+        assert_eq!(assembly.origin, 0x00f4);
+        assert_eq!(assembly.bytes, expected[0x00f4..]);
+        assert_eq!(assembly.symbols["main.loop.inner"], 0x0413);
+        assert_eq!(assembly.symbols["TOP"], 0x20);
+
+        let instruction = Instruction::new(Variant::Nmos, Opcode::LDA, Address::AbsoluteX(0x1234)).unwrap();
+        assert_eq!(instruction.encode(), [0xBD, 0x34, 0x12]);
+        assert_eq!(Instruction::decode(Variant::Nmos, &instruction.encode()), Some(instruction));
+        // the documented NOP rather than one of the undocumented ones
+        assert_eq!(Instruction::new(Variant::Nmos, Opcode::NOP, Address::Implied).unwrap().code, 0xEA);
+        assert_eq!(Instruction::new(Variant::Nmos, Opcode::LDA, Address::ZeroIndirect(0x10)), None);
+
+        let nmos = Assembler::new(Variant::Nmos);
+        assert_eq!(nmos.line("bne 0x0400", 0x0410).unwrap(), [0xD0, 0xEE]);
+        assert_eq!(nmos.line("lda <0x10", 0x0400).unwrap(), [0xA5, 0x10]);
+        assert_eq!(nmos.clone().symbol("target", 0x1234).line("jsr target", 0x0400).unwrap(), [0x20, 0x34, 0x12]);
+        let assembly = nmos.assemble("*= 0x0300\nlda #1\n*= $ + 2\nrts").unwrap();
+        assert_eq!((assembly.origin, assembly.bytes), (0x0300, vec![0xA9, 0x01, 0x00, 0x00, 0x60]));
+        let cmos = Assembler::new(Variant::Wdc65C02);
+        assert_eq!(cmos.line("lda (0x10)", 0).unwrap(), [0xB2, 0x10]);
+        assert_eq!(cmos.line("bbr0 <0x10, $", 0x0400).unwrap(), [0x0F, 0x10, 0xFD]);
+
+        let error = |line, kind| Err(AsmError { line, kind });
+        assert_eq!(nmos.assemble("lda <0x100"), error(1, AsmErrorKind::OutOfRange(0x100)));
+        assert_eq!(nmos.assemble("nop\nlda (0x10, y)"), error(2, AsmErrorKind::Syntax));
+        assert_eq!(nmos.assemble("lda label"), error(1, AsmErrorKind::UndefinedSymbol(String::from("label"))));
+        assert_eq!(nmos.assemble("bra 0"), error(1, AsmErrorKind::UnknownMnemonic(String::from("bra"))));
+        assert_eq!(nmos.assemble("jmp (0x10), y"), error(1, AsmErrorKind::InvalidOperand));
+        assert_eq!(nmos.assemble("beq $ + 0x100"), error(1, AsmErrorKind::BranchOutOfRange(0xfe)));
+        assert_eq!(nmos.assemble("a:\na:"), error(2, AsmErrorKind::DuplicateSymbol(String::from("a"))));
+        assert_eq!(nmos.assemble("*= 0x10\nnop\n*= 0"), Ok(super::Assembly { origin: 0x10, bytes: vec![0xEA], symbols: Default::default() }));
+        assert_eq!(nmos.assemble("*= 0x10\nnop\n*= 0\nnop"), error(4, AsmErrorKind::Backwards));
+    }
+
+    #[test]
+    fn ruledef() {
+        use super::{Address, Instruction, Variant};
+
+        // Generated by build.rs, each rule of src/asm/6502.asm with the bytes customasm gives for it, the mnemonic, the
+        // operand it should decode to and the opcode on its right hand side. The operand is None when build.rs doesn't
+        // know its shape.
+        type Rule = (&'static str, &'static [u8], &'static str, Option<Address>, Option<u8>);
+        let rules: &[Rule] = include!(concat!(env!("OUT_DIR"), "/ruledef.rs"));
+        let mut mismatches = Vec::new();
+        for &(source, bytes, mnemonic, addr, opcode) in rules {
+            let Some(addr) = addr else {
+                mismatches.push(format!("`{source}` has an operand build.rs doesn't know"));
+                continue;
+            };
+            let ram = bytes.iter().enumerate().map(|(i, &byte)| (0x0200 + i as u16, byte)).collect();
+            let mut cpu: Cpu = State { pc: 0x0200, ram, ..Default::default() }.into();
+            match cpu.fetch() {
+                _ if bytes.is_empty() => mismatches.push(format!("`{source}` doesn't assemble")),
+                // customasm may have picked another rule that matches the same source
+                _ if bytes.first().copied() != opcode => mismatches.push(format!("`{source}` assembles to {bytes:02x?}, but the rule's opcode is {opcode:02x?}")),
+                Ok(instruction) if instruction.opcode.to_string() == mnemonic && instruction.addr == addr && cpu.pc == 0x0200 + bytes.len() as u16 => (),
+                result => mismatches.push(format!("`{source}` assembles to {bytes:02x?}, which decodes to {result:?}, expected {mnemonic} {addr:?}")),
+            }
+        }
+        // and every documented opcode has a rule
+        for line in include_str!("../opcodes.txt").lines().filter(|line| line.split_whitespace().nth(6).is_none()) {
+            let code = u8::from_str_radix(line[2..4].trim(), 16).unwrap();
+            if !rules.iter().any(|(_, bytes, ..)| bytes.first() == Some(&code)) {
+                let instruction = Instruction::decode_opcode(Variant::Nmos, code).unwrap();
+                mismatches.push(format!("0x{code:02x} {} {:?} has no rule", instruction.opcode, instruction.addr));
+            }
+        }
+        assert!(mismatches.is_empty(), "the ruledef and opcodes.txt disagree:\n{}", mismatches.join("\n"));
+    }
+
+    #[test]
+    fn debugger() {
+        use super::{Assembler, Breakpoint, Condition, ConditionError, Debugger, Event, FlatBus, Hit, Stop, WatchKind, Watched};
+
+        let source = "
+            counter = 0x80
+            *= 0x0200
+            main:
+                ldx #0
+            .loop:
+                jsr bump
+                inx
+                cpx #8
+                bne .loop
+                brk
+            bump:
+                lda counter
+                clc
+                adc #1
+                sta counter
+                rts
+        ";
+        let assembly = Assembler::default().assemble(source).unwrap();
+        let bump = assembly.symbols["bump"] as u16;
+        let mut cpu = super::Cpu::new(Watched::new(FlatBus::from_image(&assembly.bytes, assembly.origin)), ());
+        cpu.sp = 0xff;
+        let mut debugger = Debugger::new(cpu);
+
+        let id = debugger.add(Breakpoint::pc(bump));
+        assert_eq!(debugger.run(1000), Ok(Event::Break(Hit { id, addr: bump, kind: WatchKind::Execute, value: None })));
+        assert_eq!(debugger.depth(), 1);
+        // resuming doesn't stop at the same breakpoint straight away
+        assert_eq!(debugger.run(1000), Ok(Event::Break(Hit { id, addr: bump, kind: WatchKind::Execute, value: None })));
+        assert_eq!(debugger.breakpoint(id).unwrap().hits, 2);
+        assert_eq!(debugger.step_out(1000), Ok(Event::Step));
+        assert_eq!((debugger.cpu.pc, debugger.depth()), (0x0205, 0));
+        assert_eq!(debugger.step(), Ok(Event::Step));
+        assert_eq!(debugger.cpu.pc, 0x0206);
+        debugger.remove(id);
+
+        // the condition is checked after the write
+        let id = debugger.add(Breakpoint::write(0x80..=0x80).when("[$80] == 4".parse().unwrap()));
+        assert_eq!(debugger.run(1000), Ok(Event::Break(Hit { id, addr: 0x80, kind: WatchKind::Write, value: Some(4) })));
+        assert_eq!((debugger.cpu.pc, debugger.breakpoint(id).unwrap().hits), (0x0212, 1));
+        debugger.remove(id);
+        assert_eq!(debugger.step_out(1000), Ok(Event::Step));
+        for _ in 0..3 {
+            assert_eq!(debugger.step(), Ok(Event::Step));
+        }
+        assert_eq!(debugger.cpu.pc, 0x0202);
+        assert_eq!(debugger.step_over(1000), Ok(Event::Step));
+        assert_eq!((debugger.cpu.pc, debugger.depth(), debugger.cpu.bus.bus.0[0x80]), (0x0205, 0, 5));
+        assert_eq!(debugger.run(0), Ok(Event::Limit));
+
+        // the first read is let through, and the instruction fetches don't count as reads
+        let id = debugger.add(Breakpoint::read(0x80..=0x80).ignore(1));
+        let fetches = debugger.add(Breakpoint::read(0x0200..=0x02ff));
+        assert_eq!(debugger.run(1000), Ok(Event::Break(Hit { id, addr: 0x80, kind: WatchKind::Read, value: Some(6) })));
+        assert_eq!((debugger.cpu.pc, debugger.breakpoint(id).unwrap().hits), (bump + 2, 2));
+        debugger.breakpoint_mut(id).unwrap().enabled = false;
+        assert_eq!(debugger.run(1000), Ok(Event::Stop(Stop::Brk)));
+        assert_eq!(debugger.breakpoint(fetches).unwrap().hits, 0);
+        assert_eq!(debugger.breakpoints().map(|(id, _)| id).collect::<Vec<_>>(), [id, fetches]);
+
+        let condition = |source: &str| source.parse::<Condition>();
+        let cpu = &debugger.cpu;
+        assert!(condition("x == 8 && [$80] == 8 && a == 8").unwrap().test(cpu));
+        assert!(condition("1 + 2 * 3 == 7 && 1 << 4 == 0x10 && -1 < 0 && ~0 == -1 && z && !n").unwrap().test(cpu));
+        assert_eq!(condition("pc + 0b10 - sp").unwrap().eval(cpu), cpu.pc as i64 + 2 - cpu.sp as i64);
+        assert_eq!(condition("(P & 1) == c").unwrap().to_string(), "(P & 1) == c");
+        assert_eq!(condition("a =="), Err(ConditionError { column: 5 }));
+        assert_eq!(condition("a @ 1"), Err(ConditionError { column: 3 }));
+        assert_eq!(condition("[q]"), Err(ConditionError { column: 2 }));
+        assert_eq!(condition("(a"), Err(ConditionError { column: 3 }));
+    }
+
+    /// Runs an immediate ADC or SBC with the decimal flag set.
+    fn run_decimal(opcode: u8, a: u8, value: u8, carry: bool) -> Cpu {
+        let mut cpu: Cpu = State { pc: 0x0200, a, p: 0x08 | carry as u8, ram: vec![(0x0200, opcode), (0x0201, value)], ..Default::default() }.into();
+        let instruction = cpu.fetch().unwrap();
+        cpu.execute(instruction).unwrap();
+        cpu
     }
 
     type Cpu = super::Cpu<Bus, Clock>;
@@ -750,12 +2289,26 @@ mod test {
             for i in self.ram {
                 (&mut bus as &mut dyn super::Bus).store(i.0, i.1 as u8);
             }
-            super::Cpu::with_state(bus, Clock::new(), self.x, self.y, self.p, self.a, self.s, self.pc)
+            bus.1.clear();
+            let mut cpu = super::Cpu::with_state(bus, Clock::new(), self.x, self.y, self.p, self.a, self.s, self.pc);
+            cpu.brk_policy = super::BrkPolicy::Interrupt;
+            cpu
         }
     }
 
-    #[derive(PartialEq, Eq)]
-    struct Bus(Box<[u8; 2usize.pow(16)]>);
+    /// A bus access as it appears in the test vectors, (address, value, "read" or "write").
+    type BusAccess = (u16, u8, &'static str);
+
+    /// The memory and every access made to it, the log isn't compared.
+    struct Bus(Box<[u8; 2usize.pow(16)]>, Vec<BusAccess>);
+
+    impl PartialEq for Bus {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Bus {}
     
     impl std::fmt::Debug for Bus {
         fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -764,18 +2317,35 @@ mod test {
     }
 
     impl super::Bus for Bus {
-        fn load(&self, addr: u16) -> u8 {
-            self.0[addr as usize]
+        fn load(&mut self, addr: u16) -> u8 {
+            let value = self.0[addr as usize];
+            self.1.push((addr, value, "read"));
+            value
     }
 
+        fn peek(&self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
+
         fn store(&mut self, addr: u16, value: u8) {
             self.0[addr as usize] = value;
+            self.1.push((addr, value, "write"));
     }
     }
     
+    impl super::Snapshot for Bus {
+        fn save_state(&self, out: &mut Vec<u8>) {
+            self.0.save_state(out)
+        }
+
+        fn load_state(&mut self, data: &[u8]) -> Result<(), super::SnapshotError> {
+            self.0.load_state(data)
+        }
+    }
+
     impl Bus {
         pub fn new() -> Self {
-            Self(Box::new([0;2usize.pow(16)]))
+            Self(Box::new([0;2usize.pow(16)]), Vec::new())
         }
 
         pub fn print_stack(&self) {
@@ -802,8 +2372,8 @@ mod test {
     }
 
     impl super::Clock for Clock {
-        fn cycles(&mut self, n: u8, start: Instant) {
-            self.0+= n as u64;
+        fn sync(&mut self, cycles: u64) {
+            self.0 = cycles;
         }
     }
 }
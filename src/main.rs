@@ -1,31 +1,58 @@
 use std::sync::{Mutex, Arc};
 use std::thread::JoinHandle;
-use std::time::{Instant, Duration};
 use ggez::input::keyboard::KeyCode;
 use ggez::{Context, ContextBuilder, GameResult, GameError};
 use ggez::graphics::{self, Color};
 use ggez::event::{self, EventHandler};
-use m6502::Cpu;
+use m6502::{Analysis, BrkPolicy, Bus as _, Cpu, FlatBus, MemoryMap, Step, Throttle, TrapReason, Unmapped, Variant};
 
 const GRID: u8 = 16;
 const TILE_SIZE: i32 = 32;
 const SCREEN_SIZE: f32 = GRID as f32 * TILE_SIZE as f32;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("analyse") {
+        if let Err(error) = analyse(&args[2..]) {
+            eprintln!("{error}");
+            eprintln!("usage: {} analyse FILE [--load ADDR] [--entry ADDR]... [--variant nmos|2a03|65c02] [--dot cfg|calls]", args[0]);
+            std::process::exit(2);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("functional") {
+        match functional(&args[2..]) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(error) => {
+                eprintln!("{error}");
+                eprintln!("usage: {} functional FILE [--load ADDR] [--start ADDR] [--success ADDR] [--error ADDR] [--feedback ADDR] [--variant nmos|2a03|65c02]", args[0]);
+                std::process::exit(2);
+            }
+        }
+    }
+
     let program = include_bytes!(concat!(env!("OUT_DIR"), "/program"));
 
-    let mem = Arc::new(Mutex::new([0u8; 2usize.pow(16)]));
+    // The zero page holds the direction set by the window, the display and the snake are in 0xfd00-0xfeff.
+    let zero_page = Arc::new(Mutex::new([0u8; 0x100]));
+    let display = Arc::new(Mutex::new([0u8; 0x200]));
     // Initialise the memory to random values
-    for i in mem.lock().unwrap().iter_mut() {
+    for i in zero_page.lock().unwrap().iter_mut().chain(display.lock().unwrap().iter_mut()) {
         *i = rand::random()
     };
 
-    for (i, byte) in program.iter().enumerate() {
-        mem.lock().unwrap()[0x0200 + i] = *byte;        
-    }
-
-    let bus = Bus::new(mem.clone());
-    let mut cpu = m6502::Cpu::new(bus, Clock);
+    let bus = MemoryMap::new()
+        .device(0x0000..=0x00ff, zero_page.clone())
+        // the stack
+        .ram(0x0100..=0x01ff)
+        .rom(0x0200, program)
+        .device(0xfd00..=0xfeff, display.clone())
+        // stop the game instead of carrying on after a stray write into the code or an access to nothing
+        .protect(0x0200..=0x11ff)
+        .unmapped(Unmapped::Fault);
+    // The game runs at ~80kHz, the time is checked about every 12ms.
+    let mut cpu = m6502::Cpu::new(bus, Throttle::new(83_333, 1_000));
     // Make a Context.
     let (mut ctx, event_loop) = ContextBuilder::new("6502 snake", "")
         .window_setup(ggez::conf::WindowSetup::default().title("snake on the 6502!"))
@@ -34,20 +61,22 @@ fn main() {
         .unwrap();
 
     let handle = std::thread::spawn(move || run(&mut cpu));
-    let state = State::new(&mut ctx, mem, handle);
+    let state = State::new(&mut ctx, zero_page, display, handle);
     event::run(ctx, event_loop, state);
 }
 
 struct State {
     handle: JoinHandle<()>,
-    mem: Arc<Mutex<[u8; 2usize.pow(16)]>>
+    zero_page: Arc<Mutex<[u8; 0x100]>>,
+    display: Arc<Mutex<[u8; 0x200]>>,
 }
 
 impl State {
-    pub fn new(_ctx: &mut Context, mem: Arc<Mutex<[u8; 2usize.pow(16)]>>, handle: JoinHandle<()>) -> State {
+    pub fn new(_ctx: &mut Context, zero_page: Arc<Mutex<[u8; 0x100]>>, display: Arc<Mutex<[u8; 0x200]>>, handle: JoinHandle<()>) -> State {
         State {
             handle,
-            mem
+            zero_page,
+            display,
         }
     }
 }
@@ -64,7 +93,7 @@ impl EventHandler for State {
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         let mut canvas = graphics::Canvas::from_frame(ctx, Color::WHITE);
-        for (i, v) in self.mem.lock().unwrap()[0xfd00..0xfe00].iter().enumerate() {
+        for (i, v) in self.display.lock().unwrap()[0x00..0x100].iter().enumerate() {
             let v = *v &0x03;
             let rect = graphics::Rect::new_i32((i as u8 & 0x0f) as i32 * TILE_SIZE, (i as u8 >> 4) as i32 * TILE_SIZE, TILE_SIZE, TILE_SIZE);
             canvas.draw(&graphics::Quad, graphics::DrawParam::new().dest_rect(rect).color(match v {
@@ -94,8 +123,8 @@ impl EventHandler for State {
             _repeated: bool,
         ) -> Result<(), GameError> {
         if let Some(keycode) = input.keycode {
-            let mut mem = self.mem.lock().unwrap();
-            let prev_direction = mem[0x00ff];
+            let mut zero_page = self.zero_page.lock().unwrap();
+            let prev_direction = zero_page[0xff];
             let direction = match keycode {
                 // set the direction in memory somewhere
                 KeyCode::Up => 3,
@@ -107,58 +136,132 @@ impl EventHandler for State {
             };
             
             if (!direction & 0x03) != prev_direction {
-                mem[0x00ff] = direction;
+                zero_page[0xff] = direction;
             }
         }    
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct Clock;
-
-impl m6502::Clock for Clock {
-    fn cycles(&mut self, n: u8, start: Instant) {
-        // This ensures the emulator runs at ~80kHz, it is much more reliable than using std::thread::sleep because this doesn't need a syscall
-        while start.elapsed() < Duration::from_micros(12 * n as u64) {
-          std::hint::spin_loop();
-        }  
+fn run(cpu: &mut Cpu<MemoryMap, Throttle>) {
+    loop {
+        let stop = cpu.step();
+        //println!("{:?}, PC:{:04x}, X:{}, Y:{}, S:{:08b}, A:{}, 0x0010:{}", instruction, cpu.pc, cpu.x, cpu.y, cpu.status, cpu.accumulator, cpu.bus.peek(0x0010));
+        match stop {
+            Ok(Step { stop: None, .. }) => (),
+            Ok(_) => break,
+            Err(error) => {
+                eprintln!("{error}");
+                break;
+            }
+        }
+        cpu.bus.store(0x00, 0);
+        cpu.bus.store(0x01, rand::random());
     }
 }
-
-#[derive(Debug)]
-struct Bus(Arc<Mutex<[u8; 2usize.pow(16)]>>);
-
-impl Bus {
-    pub fn new(inner: Arc<Mutex<[u8; 2usize.pow(16)]>>) -> Self {
-        Self(inner)
+/// Runs a test binary like Klaus Dormann's functional tests until it traps, returns whether it trapped at the success address.
+/// The image is loaded into a flat 64KiB memory, without a success address any trap counts as a failure.
+/// With `--error`, like the decimal test, it passes if it traps or stops with the byte at that address cleared instead.
+/// With `--feedback`, bits 0 and 1 of that address drive the IRQ and NMI lines like the interrupt test expects.
+fn functional(args: &[String]) -> Result<bool, String> {
+    let mut file = None;
+    let (mut load, mut start, mut success, mut error, mut feedback) = (0, 0x0400, None, None, None);
+    let mut variant = Variant::Nmos;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--load" => load = parse_addr(value()?)?,
+            "--start" => start = parse_addr(value()?)?,
+            "--success" => success = Some(parse_addr(value()?)?),
+            "--error" => error = Some(parse_addr(value()?)?),
+            "--feedback" => feedback = Some(parse_addr(value()?)?),
+            "--variant" => variant = parse_variant(value()?)?,
+            _ if file.is_none() => file = Some(arg),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
     }
-    pub fn print_snake(&self) {
-        println!("{:?}", &self.0.lock().unwrap()[0xfe00..0xff00]);
+    let file = file.ok_or("no file given")?;
+    let image = std::fs::read(file).map_err(|error| format!("{file}: {error}"))?;
+
+    let mut cpu = Cpu::new(FlatBus::from_image(&image, load), ());
+    cpu.variant = variant;
+    cpu.brk_policy = BrkPolicy::Interrupt;
+    cpu.pc = start;
+    let trap = cpu.run_to_trap(u64::MAX, 32, |cpu| {
+        if let Some(addr) = feedback {
+            let value = cpu.bus.0[addr as usize];
+            cpu.set_irq(value & 0x01 != 0);
+            cpu.set_nmi(value & 0x02 != 0);
+        }
+    });
+    let passed = match error {
+        Some(addr) => matches!(trap.reason, TrapReason::Loop | TrapReason::Stop(_)) && cpu.bus.0[addr as usize] == 0,
+        None => trap.reason == TrapReason::Loop && Some(trap.pc) == success,
+    };
+    if passed {
+        println!("passed, trapped at 0x{:04x} after {} cycles", trap.pc, cpu.cycles);
+    } else {
+        if let Some(addr) = error {
+            println!("the error byte is 0x{:02x}", cpu.bus.0[addr as usize]);
+        }
+        print!("failed after {} cycles, {trap}", cpu.cycles);
     }
+    Ok(passed)
 }
 
-
-impl m6502::Bus for Bus {
-    fn load(&self, addr: u16) -> u8 {
-        self.0.lock().unwrap()[addr as usize]
+/// Disassembles a binary by following the code from its entry points, prints an annotated listing or a Graphviz graph.
+/// Without `--entry`, the code starts at the NMI, reset and IRQ vectors if the image covers them, otherwise at the load address.
+fn analyse(args: &[String]) -> Result<(), String> {
+    let mut file = None;
+    let mut load = 0;
+    let mut entries = Vec::new();
+    let mut variant = Variant::Nmos;
+    let mut dot = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--load" => load = parse_addr(value()?)?,
+            "--entry" => entries.push(parse_addr(value()?)?),
+            "--variant" => variant = parse_variant(value()?)?,
+            "--dot" => dot = Some(value()?.clone()),
+            _ if file.is_none() => file = Some(arg),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
     }
+    let file = file.ok_or("no file given")?;
+    let image = std::fs::read(file).map_err(|error| format!("{file}: {error}"))?;
 
-    fn store(&mut self, addr: u16, value: u8) {
-        self.0.lock().unwrap()[addr as usize] = value;
+    if entries.is_empty() {
+        if load as usize + image.len() >= 0x10000 {
+            let bus = FlatBus::from_image(&image, load);
+            entries.extend([0xFFFA, 0xFFFC, 0xFFFE].map(|vector| bus.peek_u16(vector)));
+        } else {
+            entries.push(load);
+        }
     }
+    let analysis = Analysis::new(variant, &image, load, &entries);
+    match dot.as_deref() {
+        None => print!("{}", analysis.listing()),
+        Some("cfg") => print!("{}", analysis.cfg_dot()),
+        Some("calls") => print!("{}", analysis.calls_dot()),
+        Some(other) => return Err(format!("unknown graph {other}")),
+    }
+    Ok(())
 }
 
-fn run(cpu: &mut Cpu<Bus, Clock>) {
-    use m6502::Bus;
-    loop {
-        let instruction = cpu.fetch();
-        let brk = cpu.execute(instruction);
-        //println!("{:?}, PC:{:04x}, X:{}, Y:{}, S:{:08b}, A:{}, 0x0010:{}", instruction, cpu.pc, cpu.x, cpu.y, cpu.status, cpu.accumulator, cpu.bus.load(0x0010));
-        if brk {
-            break;
-        };
-        cpu.bus.store(0x00, 0);
-        cpu.bus.store(0x01, rand::random());
+fn parse_variant(value: &str) -> Result<Variant, String> {
+    match value {
+        "nmos" => Ok(Variant::Nmos),
+        "2a03" => Ok(Variant::Ricoh2A03),
+        "65c02" => Ok(Variant::Wdc65C02),
+        other => Err(format!("unknown variant {other}")),
     }
-}
\ No newline at end of file
+}
+
+/// Parses a hexadecimal address, with or without a `0x` or `$` prefix.
+fn parse_addr(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address {value}"))
+}
@@ -0,0 +1,399 @@
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crate::{Bus, Snapshot, SnapshotError};
+
+/// Memory mapped hardware, mounted on a range of a `MemoryMap`.
+/// The addresses passed in are offsets from the start of the range.
+pub trait Device {
+    fn load(&mut self, offset: u16) -> u8;
+    fn store(&mut self, offset: u16, value: u8);
+    /// Reads without side effects, see `Bus::peek`.
+    fn peek(&self, _offset: u16) -> u8 {
+        0
+    }
+    /// The data lines the device drives when `offset` is read, the other bits read as whatever was last on the bus.
+    /// All of them by default.
+    fn driven(&self, _offset: u16) -> u8 {
+        0xff
+    }
+    /// Appends the state of the device to `out`, for save states. Nothing by default.
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+    /// Restores the state written by `save_state`.
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        if data.is_empty() {
+            Ok(())
+        } else {
+            Err(SnapshotError::Invalid("the device has no state"))
+        }
+    }
+}
+
+/// Plain memory, for sharing with another thread through `Arc<Mutex<..>>`.
+impl<const N: usize> Device for [u8; N] {
+    fn load(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn store(&mut self, offset: u16, value: u8) {
+        if let Some(byte) = self.get_mut(offset as usize) {
+            *byte = value;
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        Snapshot::save_state(self, out)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        Snapshot::load_state(self, data)
+    }
+}
+
+impl<D: Device + ?Sized> Device for Arc<Mutex<D>> {
+    fn load(&mut self, offset: u16) -> u8 {
+        self.lock().unwrap().load(offset)
+    }
+
+    fn store(&mut self, offset: u16, value: u8) {
+        self.lock().unwrap().store(offset, value)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.lock().unwrap().peek(offset)
+    }
+
+    fn driven(&self, offset: u16) -> u8 {
+        self.lock().unwrap().driven(offset)
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.lock().unwrap().save_state(out)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.lock().unwrap().load_state(data)
+    }
+}
+
+/// What accesses to addresses nothing is mounted at do.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Unmapped {
+    /// Reads return the last value on the data bus, like on real hardware, writes are ignored.
+    #[default]
+    OpenBus,
+    /// Reads return 0, writes are ignored.
+    Zero,
+    /// Reads return 0, writes are ignored and the access is reported as a fault, see `Bus::take_fault`.
+    Fault,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// An access the memory map refused.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Fault {
+    pub addr: u16,
+    pub kind: AccessKind,
+}
+
+/// An access to an address nothing is mounted at, see `MemoryMap::log_unmapped`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct UnmappedAccess {
+    pub addr: u16,
+    pub kind: AccessKind,
+    /// The value written, or the value the read returned.
+    pub value: u8,
+}
+
+/// A bus assembled from regions of RAM, ROM, mirrors and devices.
+/// Regions mounted later take priority where they overlap.
+///
+/// The last value on the data bus is kept for open bus reads. Only `Cpu::tick` makes every bus access the hardware
+/// does, the dummy reads `execute` skips can leave a different value behind.
+#[derive(Default)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    unmapped: Unmapped,
+    /// The last value driven on the data bus.
+    last: u8,
+    fault: Option<Fault>,
+    log: Option<Vec<UnmappedAccess>>,
+    /// The ranges writes fault in.
+    protected: Vec<RangeInclusive<u16>>,
+}
+
+struct Region {
+    start: u16,
+    end: u16,
+    kind: Kind,
+}
+
+enum Kind {
+    Ram(Box<[u8]>),
+    Rom(Box<[u8]>),
+    /// Maps the region onto `len` bytes at `start`, repeating.
+    Mirror { start: u16, len: u32 },
+    Device(Box<dyn Device + Send>),
+}
+
+impl MemoryMap {
+    /// An empty address space, where every access is unmapped.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts RAM on `range`, initialised to 0.
+    pub fn ram(self, range: RangeInclusive<u16>) -> Self {
+        let len = Self::len(&range) as usize;
+        self.mount(range, Kind::Ram(vec![0; len].into_boxed_slice()))
+    }
+
+    /// Mounts `image` as read only memory at `addr`, anything past the end of the address space is dropped.
+    /// Writes are ignored.
+    pub fn rom(self, addr: u16, image: &[u8]) -> Self {
+        let len = image.len().min(0x10000 - addr as usize);
+        assert!(len > 0, "empty ROM image");
+        self.mount(addr..=addr + (len - 1) as u16, Kind::Rom(image[..len].into()))
+    }
+
+    /// Makes `range` repeat the memory at `of`, which must have been mounted before.
+    pub fn mirror(self, range: RangeInclusive<u16>, of: RangeInclusive<u16>) -> Self {
+        let len = Self::len(&of);
+        self.mount(range, Kind::Mirror { start: *of.start(), len })
+    }
+
+    /// Mounts a device on `range`.
+    pub fn device(self, range: RangeInclusive<u16>, device: impl Device + Send + 'static) -> Self {
+        self.mount(range, Kind::Device(Box::new(device)))
+    }
+
+    /// Sets what accesses to addresses nothing is mounted at do, open bus by default.
+    pub fn unmapped(mut self, unmapped: Unmapped) -> Self {
+        self.unmapped = unmapped;
+        self
+    }
+
+    /// Makes writes to `range` fault instead of changing anything, to catch guest code writing where it shouldn't.
+    pub fn protect(mut self, range: RangeInclusive<u16>) -> Self {
+        self.protected.push(range);
+        self
+    }
+
+    /// Records every access to an address nothing is mounted at, to track down mistakes in the memory map.
+    pub fn log_unmapped(mut self, log: bool) -> Self {
+        self.log = log.then(Vec::new);
+        self
+    }
+
+    /// Returns the unmapped accesses logged since the last call, oldest first.
+    pub fn take_unmapped_log(&mut self) -> Vec<UnmappedAccess> {
+        self.log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// The last value on the data bus.
+    pub fn data_bus(&self) -> u8 {
+        self.last
+    }
+
+    fn mount(mut self, range: RangeInclusive<u16>, kind: Kind) -> Self {
+        assert!(!range.is_empty(), "empty range {range:x?}");
+        self.regions.push(Region { start: *range.start(), end: *range.end(), kind });
+        self
+    }
+
+    fn len(range: &RangeInclusive<u16>) -> u32 {
+        assert!(!range.is_empty(), "empty range {range:x?}");
+        (*range.end() - *range.start()) as u32 + 1
+    }
+
+    /// Finds the region `addr` is in and the offset into it, following mirrors.
+    /// A mirror only looks at the regions mounted before it, so mirrors can't loop.
+    fn resolve(&self, mut addr: u16) -> Option<(usize, u16)> {
+        let mut end = self.regions.len();
+        loop {
+            let index = self.regions[..end].iter().rposition(|region| (region.start..=region.end).contains(&addr))?;
+            let region = &self.regions[index];
+            let offset = addr - region.start;
+            match region.kind {
+                Kind::Mirror { start, len } => {
+                    addr = start.wrapping_add((offset as u32 % len) as u16);
+                    end = index;
+                }
+                _ => return Some((index, offset)),
+            }
+        }
+    }
+
+    /// Keeps the first fault until it's taken.
+    fn fault(&mut self, addr: u16, kind: AccessKind) {
+        self.fault.get_or_insert(Fault { addr, kind });
+    }
+
+    fn unmapped_access(&mut self, addr: u16, kind: AccessKind, value: u8) {
+        if self.unmapped == Unmapped::Fault {
+            self.fault(addr, kind);
+        }
+        if let Some(log) = &mut self.log {
+            log.push(UnmappedAccess { addr, kind, value });
+        }
+    }
+
+    fn unmapped_value(&self) -> u8 {
+        match self.unmapped {
+            Unmapped::OpenBus => self.last,
+            Unmapped::Zero | Unmapped::Fault => 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut list = f.debug_list();
+        for region in &self.regions {
+            let kind = match region.kind {
+                Kind::Ram(_) => "ram",
+                Kind::Rom(_) => "rom",
+                Kind::Mirror { .. } => "mirror",
+                Kind::Device(_) => "device",
+            };
+            list.entry(&format_args!("0x{:04x}..=0x{:04x} {kind}", region.start, region.end));
+        }
+        list.finish()
+    }
+}
+
+impl Bus for MemoryMap {
+    fn load(&mut self, addr: u16) -> u8 {
+        let value = match self.resolve(addr) {
+            Some((index, offset)) => match &mut self.regions[index].kind {
+                Kind::Ram(memory) | Kind::Rom(memory) => memory[offset as usize],
+                Kind::Device(device) => {
+                    let driven = device.driven(offset);
+                    device.load(offset) & driven | self.last & !driven
+                }
+                Kind::Mirror { .. } => unreachable!(),
+            },
+            None => {
+                let value = self.unmapped_value();
+                self.unmapped_access(addr, AccessKind::Read, value);
+                value
+            }
+        };
+        self.last = value;
+        value
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        self.last = value;
+        if self.protected.iter().any(|range| range.contains(&addr)) {
+            self.fault(addr, AccessKind::Write);
+            return;
+        }
+        match self.resolve(addr) {
+            Some((index, offset)) => match &mut self.regions[index].kind {
+                Kind::Ram(memory) => memory[offset as usize] = value,
+                Kind::Rom(_) => (),
+                Kind::Device(device) => device.store(offset, value),
+                Kind::Mirror { .. } => unreachable!(),
+            },
+            None => self.unmapped_access(addr, AccessKind::Write, value),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.resolve(addr) {
+            Some((index, offset)) => match &self.regions[index].kind {
+                Kind::Ram(memory) | Kind::Rom(memory) => memory[offset as usize],
+                Kind::Device(device) => {
+                    let driven = device.driven(offset);
+                    device.peek(offset) & driven | self.last & !driven
+                }
+                Kind::Mirror { .. } => unreachable!(),
+            },
+            None => self.unmapped_value(),
+        }
+    }
+
+    fn take_fault(&mut self) -> Option<Fault> {
+        self.fault.take()
+    }
+}
+
+/// Saves the data bus, then the RAM and the devices in the order they were mounted, each device's state prefixed with
+/// its length. ROM doesn't change.
+impl Snapshot for MemoryMap {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.push(self.last);
+        for region in &self.regions {
+            match &region.kind {
+                Kind::Ram(memory) => out.extend_from_slice(memory),
+                Kind::Device(device) => {
+                    let mut state = Vec::new();
+                    device.save_state(&mut state);
+                    out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                    out.extend_from_slice(&state);
+                }
+                Kind::Rom(_) | Kind::Mirror { .. } => (),
+            }
+        }
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let invalid = SnapshotError::Invalid("memory size doesn't match");
+        let Some((&last, mut rest)) = data.split_first() else {
+            return Err(invalid);
+        };
+        // split the data up before restoring anything, so a mismatch leaves the memory alone
+        let mut states = Vec::new();
+        for region in &self.regions {
+            let len = match &region.kind {
+                Kind::Ram(memory) => memory.len(),
+                Kind::Device(_) => {
+                    let Some((len, tail)) = rest.split_first_chunk::<4>() else {
+                        return Err(invalid);
+                    };
+                    rest = tail;
+                    u32::from_le_bytes(*len) as usize
+                }
+                Kind::Rom(_) | Kind::Mirror { .. } => continue,
+            };
+            if rest.len() < len {
+                return Err(invalid);
+            }
+            let (state, tail) = rest.split_at(len);
+            states.push(state);
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(invalid);
+        }
+
+        // a device can still reject its state, everything is put back as it was if one does
+        let mut backup = Vec::new();
+        self.save_state(&mut backup);
+        self.last = last;
+        let regions = self.regions.iter_mut().filter(|region| matches!(region.kind, Kind::Ram(_) | Kind::Device(_)));
+        let result = regions.zip(states).try_for_each(|(region, state)| match &mut region.kind {
+            Kind::Ram(memory) => {
+                memory.copy_from_slice(state);
+                Ok(())
+            }
+            Kind::Device(device) => device.load_state(state),
+            Kind::Rom(_) | Kind::Mirror { .. } => unreachable!(),
+        });
+        if result.is_err() {
+            // only fails if a device can't read the state it just saved
+            let _ = self.load_state(&backup);
+        }
+        result
+    }
+}
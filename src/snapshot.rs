@@ -0,0 +1,196 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::cycle::Cycle;
+use crate::{Bus, Cpu, Variant};
+
+/// Identifies a save state file.
+const MAGIC: &[u8; 4] = b"6502";
+/// Bumped whenever the layout changes, older states are rejected rather than misread.
+const VERSION: u16 = 4;
+
+/// Implemented by buses that can save and restore their memory and device state.
+pub trait Snapshot {
+    /// Appends the state to `out`.
+    fn save_state(&self, out: &mut Vec<u8>);
+    /// Restores the state written by `save_state`, the state should be left alone if it fails.
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError>;
+}
+
+impl<const N: usize> Snapshot for [u8; N] {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        if data.len() != N {
+            return Err(SnapshotError::Invalid("memory size doesn't match"));
+        }
+        self.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl<T: Snapshot + ?Sized> Snapshot for Box<T> {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        (**self).save_state(out)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        (**self).load_state(data)
+    }
+}
+
+/// An error raised while restoring a save state.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    /// The data isn't a save state.
+    Magic,
+    /// The save state was written by an incompatible version.
+    Version(u16),
+    /// The data ended early.
+    Truncated,
+    /// A field has a value that can't be restored.
+    Invalid(&'static str),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{error}"),
+            SnapshotError::Magic => f.write_str("not a save state"),
+            SnapshotError::Version(version) => write!(f, "unsupported save state version {version}, expected {VERSION}"),
+            SnapshotError::Truncated => f.write_str("the save state is truncated"),
+            SnapshotError::Invalid(reason) => write!(f, "invalid save state: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(error: std::io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+/// Reads little endian values from a save state.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+impl<B: Bus + Snapshot, C> Cpu<B, C> {
+    /// Saves the registers, the cycle count, the interrupt lines, the progress of `tick` through an instruction and the
+    /// state of the bus.
+    /// The clock and the BRK policy aren't part of the save state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&[self.sp, self.x, self.y, self.status, self.accumulator]);
+        out.push(match self.variant {
+            Variant::Nmos => 0,
+            Variant::Ricoh2A03 => 1,
+            Variant::Wdc65C02 => 2,
+        });
+        let lines = [self.irq_line, self.nmi_line, self.nmi_pending, self.waiting, self.stopped];
+        out.push(lines.iter().enumerate().fold(0, |acc, (i, line)| acc | (*line as u8) << i));
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.overshoot.to_le_bytes());
+        self.tick_state.save(&mut out);
+
+        let mut bus = Vec::new();
+        self.bus.save_state(&mut bus);
+        out.extend_from_slice(&(bus.len() as u32).to_le_bytes());
+        out.extend_from_slice(&bus);
+        out
+    }
+
+    /// Restores a save state written by `save_state`.
+    /// Nothing is changed if the state can't be read, the bus is restored first and the registers only once it has been,
+    /// so this relies on the bus leaving itself alone when its `Snapshot::load_state` fails.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader(data);
+        if reader.bytes(MAGIC.len()).map_err(|_| SnapshotError::Magic)? != MAGIC {
+            return Err(SnapshotError::Magic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let pc = reader.u16()?;
+        let [sp, x, y, status, accumulator]: [u8; 5] = reader.bytes(5)?.try_into().unwrap();
+        let variant = match reader.u8()? {
+            0 => Variant::Nmos,
+            1 => Variant::Ricoh2A03,
+            2 => Variant::Wdc65C02,
+            _ => return Err(SnapshotError::Invalid("unknown variant")),
+        };
+        let lines = reader.u8()?;
+        let cycles = reader.u64()?;
+        let overshoot = reader.u64()?;
+        let tick_state = Cycle::load(variant, reader.bytes(Cycle::SIZE)?.try_into().unwrap())?;
+        let len = reader.u32()? as usize;
+        let bus = reader.bytes(len)?;
+        if !reader.0.is_empty() {
+            return Err(SnapshotError::Invalid("data after the end of the save state"));
+        }
+
+        self.bus.load_state(bus)?;
+        self.pc = pc;
+        self.sp = sp;
+        self.x = x;
+        self.y = y;
+        self.status = status;
+        self.accumulator = accumulator;
+        self.variant = variant;
+        self.irq_line = lines & 1 != 0;
+        self.nmi_line = lines & 2 != 0;
+        self.nmi_pending = lines & 4 != 0;
+        self.waiting = lines & 8 != 0;
+        self.stopped = lines & 16 != 0;
+        self.cycles = cycles;
+        self.overshoot = overshoot;
+        self.tick_state = tick_state;
+        Ok(())
+    }
+
+    /// Writes a save state to a file.
+    pub fn save_state_to(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        Ok(std::fs::write(path, self.save_state())?)
+    }
+
+    /// Restores a save state from a file.
+    pub fn load_state_from(&mut self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        self.load_state(&std::fs::read(path)?)
+    }
+}
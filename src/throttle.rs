@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+use crate::Clock;
+
+/// Paces the emulation to a clock rate in real time.
+/// The time is only checked once every `batch` cycles, then the thread sleeps until the wall clock has caught up.
+/// Emulated time is measured from a fixed starting point rather than per batch, so oversleeping and rounding
+/// don't add up and the average rate stays exact.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    frequency: u64,
+    batch: u64,
+    /// The time and cycle count pacing is measured from, set on the first sync.
+    origin: Option<(Instant, u64)>,
+    /// The cycle count of the next check.
+    next: u64,
+    /// How far the emulation may fall behind before it gives up on catching up.
+    max_lag: Duration,
+}
+
+impl Throttle {
+    /// Runs at `frequency` Hz, checking the time every `batch` cycles.
+    pub fn new(frequency: u64, batch: u64) -> Self {
+        Self {
+            frequency,
+            batch: batch.max(1),
+            origin: None,
+            next: 0,
+            max_lag: Duration::from_millis(100),
+        }
+    }
+
+    /// How far the emulation may fall behind real time, 100ms by default.
+    /// When the host can't keep up or the emulation was paused, the emulation carries on at the normal rate from
+    /// where it is, instead of running flat out until it has caught up.
+    pub fn with_max_lag(mut self, max_lag: Duration) -> Self {
+        self.max_lag = max_lag;
+        self
+    }
+
+    /// Starts measuring again from the next sync, for after the emulation was paused.
+    pub fn restart(&mut self) {
+        self.origin = None;
+        self.next = 0;
+    }
+
+    /// How far the emulation is ahead of real time, negative when it's behind.
+    pub fn drift(&self, cycles: u64) -> f64 {
+        match self.origin {
+            Some((start, base)) if cycles >= base => self.emulated(cycles - base).as_secs_f64() - start.elapsed().as_secs_f64(),
+            // the cycle count went back past the origin, the next sync starts measuring from there
+            _ => 0.0,
+        }
+    }
+
+    /// The real time `cycles` should take.
+    fn emulated(&self, cycles: u64) -> Duration {
+        Duration::from_nanos((cycles as u128 * 1_000_000_000 / self.frequency as u128) as u64)
+    }
+}
+
+impl Clock for Throttle {
+    fn sync(&mut self, cycles: u64) {
+        // the cycle count went back before the last check, after restoring a save state for example
+        if self.origin.is_some() && cycles < self.next.saturating_sub(self.batch) {
+            self.origin = Some((Instant::now(), cycles));
+            self.next = cycles + self.batch;
+            return;
+        }
+        if cycles < self.next {
+            return;
+        }
+        self.next = cycles + self.batch;
+        let Some((start, base)) = self.origin else {
+            self.origin = Some((Instant::now(), cycles));
+            return;
+        };
+        let target = self.emulated(cycles - base);
+        let elapsed = start.elapsed();
+        if target > elapsed {
+            std::thread::sleep(target - elapsed);
+        } else if elapsed - target > self.max_lag {
+            self.origin = Some((Instant::now(), cycles));
+        }
+    }
+}